@@ -0,0 +1,3 @@
+DROP TABLE tokens;
+
+DELETE FROM key_value WHERE key = "token_store";
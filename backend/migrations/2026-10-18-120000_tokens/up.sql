@@ -0,0 +1,11 @@
+CREATE TABLE tokens (
+    token_hash CHAR(64) PRIMARY KEY NOT NULL,
+    username VARCHAR(31) NOT NULL,
+    expiration BIGINT NOT NULL,
+
+    FOREIGN KEY (username) REFERENCES users(username) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+CREATE INDEX tokens_expiration ON tokens(expiration);
+
+INSERT OR IGNORE INTO key_value (key, value) VALUES ("token_store", "database");
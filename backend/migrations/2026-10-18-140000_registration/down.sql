@@ -0,0 +1 @@
+DELETE FROM key_value WHERE key = "registration";
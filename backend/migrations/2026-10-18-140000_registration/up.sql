@@ -0,0 +1 @@
+INSERT OR IGNORE INTO key_value (key, value) VALUES ("registration", "closed");
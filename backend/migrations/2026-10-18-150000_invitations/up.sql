@@ -0,0 +1,10 @@
+CREATE TABLE invitations (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    code_hash CHAR(64) UNIQUE NOT NULL,
+    created_by VARCHAR(31),
+    uses_left INTEGER NOT NULL,
+    created_at BIGINT NOT NULL,
+    expiration BIGINT NOT NULL,
+
+    FOREIGN KEY (created_by) REFERENCES users(username) ON DELETE SET NULL ON UPDATE CASCADE
+);
@@ -0,0 +1,4 @@
+DROP INDEX tokens_family;
+
+ALTER TABLE tokens DROP COLUMN retired;
+ALTER TABLE tokens DROP COLUMN family;
@@ -0,0 +1,4 @@
+ALTER TABLE tokens ADD COLUMN family CHAR(32);
+ALTER TABLE tokens ADD COLUMN retired BOOLEAN NOT NULL DEFAULT 0;
+
+CREATE INDEX tokens_family ON tokens(family);
@@ -0,0 +1,6 @@
+DROP INDEX tokens_username;
+
+ALTER TABLE tokens DROP COLUMN ip;
+ALTER TABLE tokens DROP COLUMN user_agent;
+ALTER TABLE tokens DROP COLUMN issuing;
+ALTER TABLE tokens DROP COLUMN jwt_type;
@@ -0,0 +1,6 @@
+ALTER TABLE tokens ADD COLUMN jwt_type VARCHAR(15) NOT NULL DEFAULT 'access_token';
+ALTER TABLE tokens ADD COLUMN issuing BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE tokens ADD COLUMN user_agent TEXT;
+ALTER TABLE tokens ADD COLUMN ip VARCHAR(45);
+
+CREATE INDEX tokens_username ON tokens(username);
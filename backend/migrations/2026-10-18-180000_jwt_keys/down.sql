@@ -0,0 +1,5 @@
+INSERT OR REPLACE INTO key_value (key, value)
+    SELECT 'jwt_secret', secret FROM jwt_keys WHERE status = 'active';
+
+DROP INDEX jwt_keys_active;
+DROP TABLE jwt_keys;
@@ -0,0 +1,16 @@
+CREATE TABLE jwt_keys (
+    kid CHAR(16) PRIMARY KEY NOT NULL,
+    secret TEXT NOT NULL,
+    status VARCHAR(15) NOT NULL DEFAULT 'inactive',
+    created_at BIGINT NOT NULL,
+    verify_until BIGINT
+);
+
+-- Only one key can be used for signing at a time
+CREATE UNIQUE INDEX jwt_keys_active ON jwt_keys(status) WHERE status = 'active';
+
+-- The old single secret becomes the key for tokens issued without a kid
+INSERT INTO jwt_keys (kid, secret, status, created_at)
+    SELECT 'legacy', value, 'active', CAST(strftime('%s', 'now') AS BIGINT)
+    FROM key_value WHERE key = 'jwt_secret';
+DELETE FROM key_value WHERE key = 'jwt_secret';
@@ -0,0 +1,13 @@
+CREATE TABLE api_tokens (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    token_hash CHAR(64) UNIQUE NOT NULL,
+    username VARCHAR(31) NOT NULL,
+    name VARCHAR(63) NOT NULL,
+    scopes TEXT NOT NULL,
+    created_at BIGINT NOT NULL,
+    expiration BIGINT,
+
+    FOREIGN KEY (username) REFERENCES users(username) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+CREATE INDEX api_tokens_username ON api_tokens(username);
@@ -0,0 +1,3 @@
+DROP INDEX recovery_codes_username;
+DROP TABLE recovery_codes;
+DROP TABLE totp;
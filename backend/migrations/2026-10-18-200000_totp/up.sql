@@ -0,0 +1,20 @@
+CREATE TABLE totp (
+    username VARCHAR(31) PRIMARY KEY NOT NULL,
+    secret VARCHAR(63) NOT NULL,
+    -- Stays false until the user confirms the enrollment with a code
+    enabled BOOLEAN NOT NULL DEFAULT 0,
+    -- Time step of the last accepted code, so a code can't be used twice
+    last_step BIGINT NOT NULL DEFAULT 0,
+
+    FOREIGN KEY (username) REFERENCES users(username) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+CREATE TABLE recovery_codes (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    username VARCHAR(31) NOT NULL,
+    code_hash VARCHAR(127) NOT NULL,
+
+    FOREIGN KEY (username) REFERENCES users(username) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+CREATE INDEX recovery_codes_username ON recovery_codes(username);
@@ -0,0 +1,6 @@
+-- Not tied to the users table, unknown usernames get throttled the same way so they can't be told apart
+CREATE TABLE login_attempts (
+    username VARCHAR(31) PRIMARY KEY NOT NULL,
+    failed_count INTEGER NOT NULL DEFAULT 0,
+    last_failed BIGINT NOT NULL
+);
@@ -0,0 +1,13 @@
+-- Named rate limiting policies, the api scopes pick them by name
+CREATE TABLE rate_limit_policies (
+    name VARCHAR(31) PRIMARY KEY NOT NULL,
+    max_requests INTEGER NOT NULL,
+    interval_seconds INTEGER NOT NULL,
+    -- What the requests are counted by: ip or user
+    key_by VARCHAR(7) NOT NULL CHECK (key_by IN ('ip', 'user'))
+);
+
+INSERT INTO rate_limit_policies (name, max_requests, interval_seconds, key_by) VALUES
+    ('auth', 10, 60, 'ip'),
+    ('reads', 300, 60, 'ip'),
+    ('writes', 60, 60, 'user');
@@ -0,0 +1,5 @@
+DROP INDEX password_resets_username;
+DROP TABLE password_resets;
+
+DROP INDEX users_email;
+ALTER TABLE users DROP COLUMN email;
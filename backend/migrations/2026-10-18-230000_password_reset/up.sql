@@ -0,0 +1,12 @@
+ALTER TABLE users ADD COLUMN email VARCHAR(254);
+CREATE UNIQUE INDEX users_email ON users(email);
+
+CREATE TABLE password_resets (
+    token_hash CHAR(64) PRIMARY KEY NOT NULL,
+    username VARCHAR(31) NOT NULL,
+    expiration BIGINT NOT NULL,
+
+    FOREIGN KEY (username) REFERENCES users(username) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+CREATE INDEX password_resets_username ON password_resets(username);
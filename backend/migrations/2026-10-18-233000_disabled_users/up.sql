@@ -0,0 +1,2 @@
+-- Disabled accounts can't log in, null means enabled
+ALTER TABLE users ADD COLUMN disabled_at BIGINT;
@@ -0,0 +1,2 @@
+-- Shown to the user when they try to log in
+ALTER TABLE users ADD COLUMN disabled_reason TEXT;
@@ -0,0 +1,24 @@
+-- Not tied to the users table, the events outlive deleted accounts
+CREATE TABLE audit_events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    -- NULL for anonymous requests and the setup commands
+    actor VARCHAR(31),
+    action VARCHAR(31) NOT NULL,
+    target TEXT,
+    ip TEXT,
+    user_agent TEXT,
+    created_at BIGINT NOT NULL
+);
+
+CREATE INDEX audit_events_created_at ON audit_events (created_at);
+
+-- Append-only, nothing can change or remove the recorded events
+CREATE TRIGGER audit_events_no_update BEFORE UPDATE ON audit_events
+BEGIN
+    SELECT RAISE(ABORT, 'audit_events are append-only');
+END;
+
+CREATE TRIGGER audit_events_no_delete BEFORE DELETE ON audit_events
+BEGIN
+    SELECT RAISE(ABORT, 'audit_events are append-only');
+END;
@@ -0,0 +1,2 @@
+DELETE FROM jwt_keys WHERE algorithm != 'HS256';
+ALTER TABLE jwt_keys DROP COLUMN algorithm;
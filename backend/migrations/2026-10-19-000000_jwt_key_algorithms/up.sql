@@ -0,0 +1,2 @@
+-- Replaced asymmetric keys stay in the keyring until their grace period ends, only their public key is kept
+ALTER TABLE jwt_keys ADD COLUMN algorithm VARCHAR(15) NOT NULL DEFAULT 'HS256';
@@ -0,0 +1,2 @@
+-- The removed scope didn't allow anything, so it isn't given back
+SELECT 1;
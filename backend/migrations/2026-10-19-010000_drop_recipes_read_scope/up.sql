@@ -0,0 +1,2 @@
+-- Reading recipes doesn't need a token, so the scope didn't allow anything
+UPDATE api_tokens SET scopes = TRIM(REPLACE(' ' || scopes || ' ', ' recipes:read ', ' '));
@@ -0,0 +1,74 @@
+use actix_web::{HttpResponse, web};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::{audit, db, models};
+use super::auth::guard::{AdminRole, Auth};
+
+
+pub fn audit_events(cfg: &mut web::ServiceConfig) {
+    cfg
+        .service(get_audit_events);
+}
+
+
+#[derive(Deserialize)]
+struct AuditQuery {
+    /// Starts at 1
+    #[serde(default = "default_page")]
+    page: i64,
+    #[serde(default = "default_per_page")]
+    per_page: i64,
+    actor: Option<String>,
+    action: Option<audit::Action>,
+    target: Option<String>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+}
+
+fn default_page() -> i64 { 1 }
+fn default_per_page() -> i64 { 50 }
+
+/// Newest events first, all the specified filters have to match
+#[actix_web::get("")]
+async fn get_audit_events(
+    _auth: Auth<AdminRole>,
+    app_data: web::Data<models::AppData>,
+    query_params: web::Query<AuditQuery>,
+) -> HttpResponse {
+    #[derive(Serialize)]
+    struct ResponseData {
+        events: Vec<audit::Entry>,
+        page: i64,
+        per_page: i64,
+        /// Number of all the matching events
+        total: i64,
+    }
+
+    if query_params.page < 1 || ! (1..=100).contains(&query_params.per_page) {
+        return HttpResponse::BadRequest().finish();
+    }
+
+    let filter = db::audit_events::Filter {
+        actor: query_params.actor.clone(),
+        action: query_params.action.map(|val| val.to_string()),
+        target: query_params.target.clone(),
+        since: query_params.since.map(|val| val.timestamp()),
+        until: query_params.until.map(|val| val.timestamp()),
+    };
+
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    let offset = (query_params.page - 1) * query_params.per_page;
+    let (events, total) = match db::audit_events::get_page(&mut conn, &filter, offset, query_params.per_page) {
+        Ok(val) => val,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    HttpResponse::Ok().json(ResponseData {
+        events: events.into_iter().map(audit::Entry::from).collect(),
+        page: query_params.page,
+        per_page: query_params.per_page,
+        total,
+    })
+}
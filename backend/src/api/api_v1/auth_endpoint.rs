@@ -1,18 +1,29 @@
 use actix_web::{cookie, HttpResponse, HttpRequest, web};
-use serde::Deserialize;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
 
 use super::db::prelude::*;
-use super::{auth, db, models};
-use super::auth::jwt::JwtType;
-use super::auth::CookieName;
+use super::{auth, db, models, validating};
+use super::audit::{Action, Event};
+use super::auth::jwt::{JwtConfig, JwtType};
+use super::auth::token_storage::{ClientInfo, StorageError};
+use super::auth::{CookieName, RegistrationPolicy, Role};
+use crate::mail::{Mail, MailTransport};
 
 
 pub fn auth(cfg: &mut web::ServiceConfig) {
     cfg
         .service(log_in)
+        .service(log_in_mfa)
         .service(log_out)
         .service(refresh)
-        .service(change_password);
+        .service(change_password)
+        .service(forgot_password)
+        .service(reset_password)
+        .service(register)
+        .service(credential_policy);
 }
 
 
@@ -22,6 +33,59 @@ macro_rules! chrono_to_cookie_time {
     };
 }
 
+/// Path of the refresh token cookie, it is only needed by the auth endpoints
+const REFRESH_COOKIE_PATH: &str = "/api/v1/auth";
+
+fn refresh_cookie<'a>(value: String, expiration: cookie::time::OffsetDateTime) -> cookie::Cookie<'a> {
+    cookie::Cookie::build(CookieName::RefreshToken.to_string(), value)
+        .path(REFRESH_COOKIE_PATH)
+        .expires(expiration)
+        .secure(true)
+        .http_only(true) 
+        .finish()
+}
+
+fn access_cookie<'a>(value: String, expiration: cookie::time::OffsetDateTime) -> cookie::Cookie<'a> {
+    cookie::Cookie::build(CookieName::AccessToken.to_string(), value)
+        .path("/")
+        .expires(expiration)
+        .secure(true)
+        .http_only(true) 
+        .finish()
+}
+
+
+/// ## How the tokens are handed to the client
+/// Cookies for browsers, the response body for clients that send them back in the `Authorization: Bearer` header
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+enum ResponseMode {
+    #[default]
+    #[serde(rename = "cookie")] Cookie,
+    #[serde(rename = "token")] Token,
+}
+
+/// Body of the token response mode
+#[derive(Serialize)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: String,
+    /// When the access token expires
+    expires_at: DateTime<Utc>,
+}
+
+/// Creates and registers an access token from the same family as the refresh token
+fn new_access_token(jwt_conf: &JwtConfig, username: &str, role: Role, family: &str) -> Result<(String, DateTime<Utc>), StorageError> {
+    let access_jwt = jwt_conf.new_jwt(
+        JwtType::AccessToken,
+        username,
+        role,
+        Some(family),
+    );
+    let expiration_time = access_jwt.get_expiration();
+    let serialized_access_jwt = jwt_conf.register(access_jwt)?;
+    Ok((serialized_access_jwt.to_string(), expiration_time))
+}
+
 
 #[derive(Deserialize)]
 struct Credentials {
@@ -29,22 +93,42 @@ struct Credentials {
     password: String,
 }
 
+#[derive(Debug, Deserialize)]
+struct LogInParams {
+    #[serde(default)]
+    mode: ResponseMode,
+}
+
 #[actix_web::post("/log_in")]
 async fn log_in(
+    query_params: web::Query<LogInParams>,
     req: HttpRequest,
     app_data: web::Data<models::AppData>,
     credentials: web::Json<Credentials>,
 ) -> HttpResponse {
-    // Check if user is already logged in
-    if let Some(val) = req.cookie(&CookieName::RefreshToken.to_string()) {
-        let jwt = app_data.jwt_conf.jwt_from_str(val.value().to_string());
-        if app_data.jwt_conf.validate(jwt).is_none() {
-            return HttpResponse::Ok().body("Already logged in");
+    // Check if user is already logged in, only browsers keep the token in a cookie
+    if query_params.mode == ResponseMode::Cookie {
+        if let Some(val) = req.cookie(&CookieName::RefreshToken.to_string()) {
+            let jwt = app_data.jwt_conf.jwt_from_str(val.value().to_string());
+            if app_data.jwt_conf.validate(jwt).is_some() {
+                return HttpResponse::Ok().body("Already logged in");
+            }
         }
     }
 
     let mut conn: db::Conn = super::get_conn!(app_data.pool);
 
+    // Slow down guessing the password of a single user
+    let attempt = match db::login_attempts::get(&mut conn, &credentials.username) {
+        Ok(val) => val,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    if let Some(retry_after) = auth::lockout::retry_after(attempt.as_ref(), Utc::now().timestamp()) {
+        return HttpResponse::TooManyRequests()
+            .append_header((actix_web::http::header::RETRY_AFTER, retry_after))
+            .finish();
+    }
+
     // Check if specified user exists
     let query_result = users_dsl::users
         .find(&credentials.username)
@@ -53,31 +137,220 @@ async fn log_in(
     // Get user data from db
     let user_data: models::User = match query_result {
         Ok(val) => val,
-        Err(diesel::result::Error::NotFound) => return HttpResponse::Unauthorized().finish(),
+        Err(diesel::result::Error::NotFound) => {
+            auth::verify_dummy_password(&credentials.password);
+            // Usernames that can't exist aren't worth storing
+            if ! app_data.credential_policy.username.check(&credentials.username).is_empty() {
+                return HttpResponse::Unauthorized().finish();
+            }
+            return failed_log_in(&req, &mut conn, &credentials.username);
+        },
         Err(_) => return HttpResponse::InternalServerError().finish(),
     };
 
     // Verify password
     if ! auth::verify_password(&credentials.password, &user_data.password_hash) {
-        return HttpResponse::Unauthorized().finish();
+        return failed_log_in(&req, &mut conn, &credentials.username);
     }
 
-    // Auth successful, respond with a cookie
+    // Only told after the password, so it doesn't reveal which accounts are disabled
+    if user_data.disabled_at.is_some() {
+        return account_disabled(user_data.disabled_reason);
+    }
+
+    // Upgrade hashes made with older parameters while the password is at hand
+    if auth::needs_rehash(&user_data.password_hash) {
+        let result = diesel::update(users_dsl::users.find(&user_data.username))
+            .set(users_dsl::password_hash.eq(auth::hash_password(&credentials.password)))
+            .execute(&mut conn);
+        if let Err(err) = result {
+            tracing::error!(username = %user_data.username, %err, "Couldn't rehash the password");
+        }
+    }
+
+    let role: Role = user_data.role.parse().unwrap_or_default();
+
+    // Users with a second factor get a short lived token that has to be exchanged along with the code
+    let totp = match db::totp::get(&mut conn, &user_data.username) {
+        Ok(val) => val,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    if totp.is_some_and(|val| val.enabled) {
+        #[derive(Serialize)]
+        struct MfaResponse {
+            mfa_token: String,
+            expires_at: DateTime<Utc>,
+        }
+
+        let jwt_conf = &app_data.jwt_conf;
+        let mfa_jwt = jwt_conf.new_jwt(
+            JwtType::MfaPending,
+            &user_data.username,
+            role,
+            None,
+        );
+        let expires_at = mfa_jwt.get_expiration();
+        return match jwt_conf.register(mfa_jwt) {
+            Ok(val) => HttpResponse::Ok().json(MfaResponse { mfa_token: val.to_string(), expires_at }),
+            Err(_) => HttpResponse::InternalServerError().finish(),
+        };
+    }
+
+    Event::new(&req, Some(&user_data.username), Action::LogIn).record(&mut conn);
+    start_session(&req, &mut conn, &app_data.jwt_conf, &user_data.username, role, query_params.mode)
+}
+
+
+#[derive(Deserialize)]
+struct MfaData {
+    mfa_token: String,
+    /// Either a code from the authenticator app or one of the recovery codes
+    code: String,
+}
+
+/// Second step of logging in for users with two-factor authentication
+#[actix_web::post("/log_in/mfa")]
+async fn log_in_mfa(
+    query_params: web::Query<LogInParams>,
+    req: HttpRequest,
+    app_data: web::Data<models::AppData>,
+    mfa_data: web::Json<MfaData>,
+) -> HttpResponse {
     let jwt_conf = &app_data.jwt_conf;
 
+    let jwt = jwt_conf.jwt_from_str(mfa_data.mfa_token.clone());
+    let claims = match jwt_conf.validate(jwt.clone()) {
+        Some(val) if val.get_jwt_type() == JwtType::MfaPending => val,
+        _ => return HttpResponse::Unauthorized().finish(),
+    };
+    // Every token allows only one try, so the code can't be guessed without the password
+    if jwt_conf.invalidate(jwt).is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let username = claims.get_username();
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    // The failures are only cleared after the second factor, so the lockout applies to the codes as well
+    let attempt = match db::login_attempts::get(&mut conn, &username) {
+        Ok(val) => val,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    if let Some(retry_after) = auth::lockout::retry_after(attempt.as_ref(), Utc::now().timestamp()) {
+        return HttpResponse::TooManyRequests()
+            .append_header((actix_web::http::header::RETRY_AFTER, retry_after))
+            .finish();
+    }
+
+    let totp = match db::totp::get(&mut conn, &username) {
+        Ok(Some(val)) if val.enabled => val,
+        Ok(_) => return HttpResponse::Unauthorized().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    let is_valid = match auth::totp::verify(&totp.secret, &mfa_data.code, Utc::now().timestamp(), totp.last_step) {
+        Some(step) => db::totp::set_last_step(&mut conn, &username, step).map(|_| true),
+        None => db::totp::use_recovery_code(&mut conn, &username, &mfa_data.code),
+    };
+    match is_valid {
+        Ok(true) => (),
+        Ok(false) => return failed_log_in(&req, &mut conn, &username),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    }
+
+    // The account could have been disabled or its role changed since the password was checked
+    let role = match db::users::current_role(&mut conn, &username) {
+        Ok(Some(val)) => val,
+        Ok(None) => return json_error(HttpResponse::Forbidden(), "Account disabled"),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    Event::new(&req, Some(&username), Action::LogIn).record(&mut conn);
+    start_session(&req, &mut conn, jwt_conf, &username, role, query_params.mode)
+}
+
+
+/// Counts the failure towards the lockout of the username
+fn failed_log_in(req: &HttpRequest, conn: &mut db::Conn, username: &str) -> HttpResponse {
+    if db::login_attempts::record_failure(conn, username).is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+    Event::new(req, None, Action::LogInFailed).target(username).record(conn);
+    HttpResponse::Unauthorized().finish()
+}
+
+
+/// Tells the user why they can't log in
+fn account_disabled(reason: Option<String>) -> HttpResponse {
+    #[derive(Serialize)]
+    struct ResponseData {
+        error: &'static str,
+        reason: Option<String>,
+    }
+
+    HttpResponse::Forbidden().json(ResponseData {
+        error: "Account disabled",
+        reason,
+    })
+}
+
+/// Error with a message in the body, so the client can tell the failures apart
+fn json_error(mut response: actix_web::HttpResponseBuilder, error: &'static str) -> HttpResponse {
+    #[derive(Serialize)]
+    struct ResponseData {
+        error: &'static str,
+    }
+
+    response.json(ResponseData { error })
+}
+
+/// Lists the broken rules, so the client can show them
+pub(super) fn invalid_credentials(violations: Vec<validating::Violation>) -> HttpResponse {
+    #[derive(Serialize)]
+    struct ResponseData {
+        violations: Vec<validating::Violation>,
+    }
+
+    HttpResponse::BadRequest().json(ResponseData { violations })
+}
+
+
+/// Issues the refresh token of a new session, along with the access token in the token response mode.
+/// The failed log ins of the user are forgotten
+fn start_session(req: &HttpRequest, conn: &mut db::Conn, jwt_conf: &JwtConfig, username: &str, role: Role, mode: ResponseMode) -> HttpResponse {
+    if db::login_attempts::clear(conn, username).is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
     let jwt_data = jwt_conf.new_jwt(
         JwtType::RefreshToken,
-        &credentials.username
+        username,
+        role,
+        None,
     );
+    let family = jwt_data.get_family();
     let expiration_time = jwt_data.get_expiration();
-    let jwt_string = jwt_conf.register(jwt_data).to_string();
 
-    let cookie = cookie::Cookie::build(CookieName::RefreshToken.to_string(), jwt_string)
-        .path("/auth")
-        .expires(chrono_to_cookie_time!(expiration_time))
-        .secure(true)
-        .http_only(true) 
-        .finish();
+    // Remember where the user logged in from, so it can be shown in the session list
+    let jwt_string = match jwt_conf.register_session(jwt_data, ClientInfo::from_request(req)) {
+        Ok(val) => val.to_string(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    // Clients without cookies get both tokens right away
+    if mode == ResponseMode::Token {
+        let (access_token, expires_at) = match new_access_token(jwt_conf, username, role, &family) {
+            Ok(val) => val,
+            Err(_) => return HttpResponse::InternalServerError().finish(),
+        };
+        return HttpResponse::Ok().json(TokenResponse {
+            access_token,
+            refresh_token: jwt_string,
+            expires_at,
+        });
+    }
+
+    let cookie = refresh_cookie(jwt_string, chrono_to_cookie_time!(expiration_time));
 
     HttpResponse::Ok().cookie(cookie).finish()
 }
@@ -90,54 +363,59 @@ async fn log_out(
 ) -> HttpResponse {
     let jwt_conf = &app_data.jwt_conf;
 
-    // Invalidate the user refresh token
-    let refresh_token = match req.cookie(&CookieName::RefreshToken.to_string()) {
-        Some(val) => val.value().to_string(),
+    // Invalidate the user refresh token along with everything issued from it
+    let refresh_token = match auth::request_token(&req, CookieName::RefreshToken) {
+        Some(val) => val,
         None => return HttpResponse::Unauthorized().finish(),
     };
     let jwt = jwt_conf.jwt_from_str(refresh_token);
-    jwt_conf.invalidate(jwt);
+    let claims = jwt_conf.validate(jwt.clone());
+    // Tokens issued before families existed have an empty one, they can only be invalidated by themselves
+    let query_result = match &claims {
+        Some(claims) if ! claims.get_family().is_empty() => jwt_conf.invalidate_family(&claims.get_family()),
+        _ => jwt_conf.invalidate(jwt),
+    };
+    if query_result.is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+    if let Some(claims) = claims {
+        let mut conn: db::Conn = super::get_conn!(app_data.pool);
+        Event::new(&req, Some(&claims.get_username()), Action::LogOut).record(&mut conn);
+    }
 
     // Invalidate the user access token if exists
     if let Some(val) = req.cookie(&CookieName::AccessToken.to_string()) {
         let jwt_string = val.value();
         let jwt = jwt_conf.jwt_from_str(
             jwt_string.to_string());
-        jwt_conf.invalidate(jwt);
+        if jwt_conf.invalidate(jwt).is_err() {
+            return HttpResponse::InternalServerError().finish();
+        }
     }
     
-    let refresh_cookie = cookie::Cookie::build(CookieName::RefreshToken.to_string(), "")
-        .path("/auth")
-        .expires(cookie::time::OffsetDateTime::UNIX_EPOCH)
-        .secure(true)
-        .http_only(true) 
-        .finish();
-
-    let access_cookie = cookie::Cookie::build(CookieName::AccessToken.to_string(), "")
-        .path("/")
-        .expires(cookie::time::OffsetDateTime::UNIX_EPOCH)
-        .secure(true)
-        .http_only(true) 
-        .finish();
+    let refresh_cookie = refresh_cookie(String::new(), cookie::time::OffsetDateTime::UNIX_EPOCH);
+    let access_cookie = access_cookie(String::new(), cookie::time::OffsetDateTime::UNIX_EPOCH);
 
     HttpResponse::Ok().cookie(refresh_cookie).cookie(access_cookie).finish()
 }
 
 
 #[derive(Debug, Deserialize)]
-struct RedirectParams {
+struct RefreshParams {
     from: Option<String>,
+    #[serde(default)]
+    mode: ResponseMode,
 }
 
 #[actix_web::get("/refresh")]
 async fn refresh(
-    query_params: web::Query<RedirectParams>,
+    query_params: web::Query<RefreshParams>,
     req: HttpRequest,
     app_data: web::Data<models::AppData>,
 ) -> HttpResponse {
     // Try to get the refresh token
-    let refresh_token = match req.cookie(&CookieName::RefreshToken.to_string()) {
-        Some(val) => val.value().to_string(),
+    let refresh_token = match auth::request_token(&req, CookieName::RefreshToken) {
+        Some(val) => val,
         None => return HttpResponse::Unauthorized().finish(),
     };
 
@@ -145,50 +423,82 @@ async fn refresh(
 
     // Parse refresh token
     let refresh_jwt = jwt_conf.jwt_from_str(refresh_token);
-    let claims = match jwt_conf.validate(refresh_jwt) {
-        Some(val) => val,
-        None => return HttpResponse::Unauthorized().finish(),
+    let claims = match jwt_conf.validate(refresh_jwt.clone()) {
+        Some(val) if val.get_jwt_type() == JwtType::RefreshToken => val,
+        _ => return HttpResponse::Unauthorized().finish(),
     };
 
-    // Invalidate old refresh token if exists
-    if let Some(val) = req.cookie(&CookieName::AccessToken.to_string()) {
-        let jwt = jwt_conf.jwt_from_str(val.value().to_string());
-        jwt_conf.invalidate(jwt);
+    // Disabled users don't get new tokens, even if their session wasn't revoked.
+    // The new tokens get the current role, so a changed role applies to running sessions
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+    let role = match db::users::current_role(&mut conn, &claims.get_username()) {
+        Ok(Some(val)) => val,
+        Ok(None) => return HttpResponse::Unauthorized().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    // Invalidate the old access tokens of the session, whether they were kept in a cookie or by the client.
+    // Bearer clients send the refresh token in the header, so the old access token can't be read from the request
+    let family = claims.get_family();
+    if ! family.is_empty() && jwt_conf.invalidate_access_tokens(&family).is_err() {
+        return HttpResponse::InternalServerError().finish();
     }
 
-    // Create an register the access token
-    let access_jwt = jwt_conf.new_jwt(
-        JwtType::AccessToken,
-        &claims.get_username()
+    // Exchange the refresh token for a new one from the same family, the old one gets retired.
+    // Tokens issued before families existed get a new one, so they don't all end up sharing the empty family
+    let new_refresh_jwt = jwt_conf.new_jwt(
+        JwtType::RefreshToken,
+        &claims.get_username(),
+        role,
+        (! family.is_empty()).then_some(family.as_str()),
     );
-    let expiration_time = access_jwt.get_expiration();
-    let serialized_access_jwt = jwt_conf.register(access_jwt).to_string();
+    let family = new_refresh_jwt.get_family();
+    let refresh_expiration_time = new_refresh_jwt.get_expiration();
+    let serialized_refresh_jwt = match jwt_conf.rotate(refresh_jwt, new_refresh_jwt) {
+        Ok(Some(val)) => val.to_string(),
+        // Another request got to it first
+        Ok(None) => return HttpResponse::Unauthorized().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
 
-    // Build a cookie
-    let access_cookie = cookie::Cookie::build(
-        CookieName::AccessToken.to_string(),
-        serialized_access_jwt
-    )
-        .path("/")
-        .expires(chrono_to_cookie_time!(expiration_time))
-        .secure(true)
-        .http_only(true) 
-        .finish();
+    // Create an register the access token
+    let (serialized_access_jwt, expiration_time) = match new_access_token(
+        jwt_conf,
+        &claims.get_username(),
+        role,
+        &family,
+    ) {
+        Ok(val) => val,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    if query_params.mode == ResponseMode::Token {
+        return HttpResponse::Ok().json(TokenResponse {
+            access_token: serialized_access_jwt,
+            refresh_token: serialized_refresh_jwt,
+            expires_at: expiration_time,
+        });
+    }
+
+    // Build the cookies
+    let refresh_cookie = refresh_cookie(serialized_refresh_jwt, chrono_to_cookie_time!(refresh_expiration_time));
+    let access_cookie = access_cookie(serialized_access_jwt, chrono_to_cookie_time!(expiration_time));
 
     // Send a response
     if let Some(redirect_path) = &query_params.from {
         HttpResponse::Found()
+            .cookie(refresh_cookie)
             .cookie(access_cookie)
             .append_header(("Location", redirect_path.as_str()))
             .finish()
     } else {
-        HttpResponse::Ok().cookie(access_cookie).finish()
+        HttpResponse::Ok().cookie(refresh_cookie).cookie(access_cookie).finish()
     }
 }
 
 
 #[derive(Deserialize)]
-struct ChangePsswordData {
+struct ChangePasswordData {
     password: String,
     new_password: String,
 }
@@ -197,51 +507,608 @@ struct ChangePsswordData {
 async fn change_password(
     req: HttpRequest,
     app_data: web::Data<models::AppData>,
-    change_password_data: web::Json<ChangePsswordData>,
+    change_password_data: web::Json<ChangePasswordData>,
 ) -> HttpResponse {
     let jwt_conf = &app_data.jwt_conf;
 
     // Try to get the refresh token
-    let refresh_token = match req.cookie(&CookieName::RefreshToken.to_string()) {
-        Some(val) => val.value().to_string(),
-        None => return HttpResponse::Unauthorized().finish(),
+    let refresh_token = match auth::request_token(&req, CookieName::RefreshToken) {
+        Some(val) => val,
+        None => return json_error(HttpResponse::Unauthorized(), "Not logged in"),
     };
-    // Validate and deserialize it
+    // Validate and deserialize it, an access token isn't enough
     let jwt = jwt_conf.jwt_from_str(refresh_token);
     let claims = match jwt_conf.validate(jwt) {
-        Some(val) => val,
-        None => return HttpResponse::Unauthorized().finish(),
+        Some(val) if val.get_jwt_type() == JwtType::RefreshToken => val,
+        _ => return json_error(HttpResponse::Unauthorized(), "Not logged in"),
     };
+    let username = claims.get_username();
 
     let mut conn: db::Conn = super::get_conn!(app_data.pool);
 
     // Query the db
     let query_result = users_dsl::users
         .select(users_dsl::password_hash)
-        .find(claims.get_username())
+        .find(&username)
         .first(&mut conn);
 
     // If the logged in user not found, retune an internal error
     let pw_hash: String = match query_result {
         Ok(val) => val,
-        Err(_) => return HttpResponse::InternalServerError().finish(),
+        Err(_) => return json_error(HttpResponse::InternalServerError(), "Internal error"),
     };
 
     // Check if the passed password is valid
     if ! auth::verify_password(&change_password_data.password, &pw_hash) {
-        return HttpResponse::Unauthorized().finish();
+        return json_error(HttpResponse::Unauthorized(), "Wrong password");
+    }
+
+    // The new password follows the same rules as on registration
+    let mut violations = app_data.credential_policy.password.check(&change_password_data.new_password);
+    if change_password_data.new_password == change_password_data.password {
+        violations.push(validating::Violation::PasswordReused);
+    }
+    if ! violations.is_empty() {
+        return invalid_credentials(violations);
     }
 
     // Hash and set the new password
     let new_pw_hash = auth::hash_password(&change_password_data.new_password);
-    let query_result = diesel::update(users_dsl::users.find(claims.get_username()))
+    let query_result = diesel::update(users_dsl::users.find(&username))
         .set(users_dsl::password_hash.eq(new_pw_hash))
         .execute(&mut conn);
 
     // Check for an error
+    if query_result.is_err() {
+        return json_error(HttpResponse::InternalServerError(), "Internal error");
+    }
+
+    // Revoke everything but the current session, someone else might know the old password
+    if jwt_conf.invalidate_sessions(&username, Some(&claims.get_family())).is_err()
+        || db::api_tokens::revoke_all(&mut conn, &username).is_err()
+        || db::password_resets::remove(&mut conn, &username).is_err() {
+        return json_error(HttpResponse::InternalServerError(), "Internal error");
+    }
+
+    Event::new(&req, Some(&username), Action::PasswordChanged).record(&mut conn);
+    HttpResponse::Ok().finish()
+}
+
+
+#[derive(Deserialize)]
+struct ForgotPasswordData {
+    /// Either the username or the email of the account
+    login: String,
+}
+
+/// Mails a password reset link to the user, responds the same way whether the account exists or not
+#[actix_web::post("/forgot_password")]
+async fn forgot_password(
+    req: HttpRequest,
+    app_data: web::Data<models::AppData>,
+    forgot_password_data: web::Json<ForgotPasswordData>,
+) -> HttpResponse {
+    let mailer = match &app_data.mailer {
+        Some(val) => val.clone(),
+        None => return HttpResponse::ServiceUnavailable().finish(),
+    };
+
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    let login = forgot_password_data.login.trim();
+    // Disabled accounts don't get a link either
+    let query_result = users_dsl::users
+        .select((users_dsl::username, users_dsl::email))
+        .filter(users_dsl::username.eq(login).or(users_dsl::email.eq(login)))
+        .filter(users_dsl::disabled_at.is_null())
+        .first::<(String, Option<String>)>(&mut conn)
+        .optional();
+    let (username, email) = match query_result {
+        Ok(Some((username, Some(email)))) => (username, email),
+        Ok(_) => return HttpResponse::Ok().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    let link = match password_reset_link(&mut conn, &username) {
+        Ok(val) => val,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    // Don't wait for the mail server, so the response doesn't tell if the account exists
+    send_password_reset_mail(mailer, &username, email, &link);
+
+    Event::new(&req, None, Action::PasswordResetRequested).target(&username).record(&mut conn);
+    HttpResponse::Ok().finish()
+}
+
+
+/// Creates a new password reset token for the user
+/// ### Returns
+/// The link to the page where the user sets the new password
+pub(super) fn password_reset_link(conn: &mut db::Conn, username: &str) -> Result<String, DieselError> {
+    let token = db::password_resets::create(conn, username, *crate::PASSWORD_RESET_DURATION)?;
+    let public_url = db::key_value::get(conn, "public_url").unwrap_or_else(|_| "http://localhost".to_owned());
+    Ok(format!("{}/reset_password?token={}", public_url.trim_end_matches('/'), token))
+}
+
+/// Sends the link in the background, the errors only get logged
+pub(super) fn send_password_reset_mail(mailer: std::sync::Arc<dyn MailTransport>, username: &str, email: String, link: &str) {
+    let mail = Mail {
+        to: email,
+        subject: "CookBook password reset".to_owned(),
+        body: format!(
+            "Hi {},\n\nUse this link to set a new password, it is valid for {} minutes:\n{}\n\nIf you didn't ask for it, you can ignore this mail.",
+            username,
+            crate::PASSWORD_RESET_DURATION.num_minutes(),
+            link,
+        ),
+    };
+    actix_web::rt::task::spawn_blocking(move || {
+        if let Err(err) = mailer.send(&mail) {
+            tracing::error!(to = %mail.to, %err, "Couldn't send the password reset mail");
+        }
+    });
+}
+
+
+#[derive(Deserialize)]
+struct ResetPasswordData {
+    token: String,
+    new_password: String,
+}
+
+#[actix_web::post("/reset_password")]
+async fn reset_password(
+    req: HttpRequest,
+    app_data: web::Data<models::AppData>,
+    reset_password_data: web::Json<ResetPasswordData>,
+) -> HttpResponse {
+    let violations = app_data.credential_policy.password.check(&reset_password_data.new_password);
+    if ! violations.is_empty() {
+        return invalid_credentials(violations);
+    }
+
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    let username = match db::password_resets::consume(&mut conn, &reset_password_data.token) {
+        Ok(Some(val)) => val,
+        Ok(None) => return HttpResponse::Unauthorized().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    let new_pw_hash = auth::hash_password(&reset_password_data.new_password);
+    let query_result = diesel::update(users_dsl::users.find(&username))
+        .set(users_dsl::password_hash.eq(new_pw_hash))
+        .execute(&mut conn);
     if query_result.is_err() {
         return HttpResponse::InternalServerError().finish();
     }
 
+    // The old password might be known to someone else, revoke everything like change_password does and let the user in again
+    if app_data.jwt_conf.invalidate_sessions(&username, None).is_err()
+        || db::api_tokens::revoke_all(&mut conn, &username).is_err()
+        || db::password_resets::remove(&mut conn, &username).is_err()
+        || db::login_attempts::clear(&mut conn, &username).is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    Event::new(&req, Some(&username), Action::PasswordReset).record(&mut conn);
     HttpResponse::Ok().finish()
 }
+
+
+#[derive(Deserialize)]
+struct RegistrationData {
+    username: String,
+    password: String,
+    /// Only needed when registration is invite only
+    invitation_code: Option<String>,
+    /// Needed for resetting a forgotten password
+    email: Option<String>,
+}
+
+#[actix_web::post("/register")]
+async fn register(
+    req: HttpRequest,
+    app_data: web::Data<models::AppData>,
+    registration_data: web::Json<RegistrationData>,
+) -> HttpResponse {
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    // Missing or unknown setting means that registration is closed
+    let policy: RegistrationPolicy = match db::key_value::get(&mut conn, "registration") {
+        Ok(val) => val.parse().unwrap_or_default(),
+        Err(DieselError::NotFound) => RegistrationPolicy::default(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let invitation_code = match (policy, &registration_data.invitation_code) {
+        (RegistrationPolicy::Open, _) => None,
+        (RegistrationPolicy::InviteOnly, Some(code)) => Some(code),
+        (RegistrationPolicy::InviteOnly, None) | (RegistrationPolicy::Closed, _) => return HttpResponse::Forbidden().finish(),
+    };
+
+    let mut violations = app_data.credential_policy.check(&registration_data.username, &registration_data.password);
+    if registration_data.email.as_deref().is_some_and(|val| ! validating::is_valid_email(val)) {
+        violations.push(validating::Violation::InvalidEmail);
+    }
+    if ! violations.is_empty() {
+        return invalid_credentials(violations);
+    }
+
+    let new_user = models::User {
+        username: registration_data.username.clone(),
+        password_hash: auth::hash_password(&registration_data.password),
+        role: Role::User.to_string(),
+        email: registration_data.email.clone(),
+        disabled_at: None,
+        disabled_reason: None,
+    };
+
+    // Only use up the invitation if the account actually gets created
+    let query_result = conn.transaction::<_, DieselError, _>(|conn| {
+        if let Some(code) = invitation_code {
+            if ! db::invitations::consume(conn, code)? {
+                return Ok(false);
+            }
+        }
+
+        diesel::insert_into(users_dsl::users)
+            .values(&new_user)
+            .execute(conn)?;
+        Ok(true)
+    });
+
+    match query_result {
+        Ok(true) => {
+            Event::new(&req, Some(&new_user.username), Action::Register).record(&mut conn);
+            HttpResponse::Ok().finish()
+        },
+        Ok(false) => HttpResponse::Forbidden().finish(),
+        Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => HttpResponse::Conflict().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+
+/// The rules for new usernames and passwords, so the client can check them before sending
+#[actix_web::get("/credential_policy")]
+async fn credential_policy(app_data: web::Data<models::AppData>) -> HttpResponse {
+    HttpResponse::Ok().json(&app_data.credential_policy)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+    use crate::auth::token_storage::MemoryStorage;
+    use diesel::connection::SimpleConnection;
+
+    const PASSWORD: &str = "Password1!";
+    const NEW_PASSWORD: &str = "NewPassword2?";
+
+    /// A fresh database in a temporary file with a single user
+    fn app_data(name: &str) -> (web::Data<models::AppData>, std::path::PathBuf) {
+        let (pool, path) = db::test_pool(name);
+        let mut conn = pool.get().unwrap();
+        diesel::insert_into(users_dsl::users)
+            .values(models::User {
+                username: "chef".to_owned(),
+                password_hash: auth::hash_password(PASSWORD),
+                role: Role::User.to_string(),
+                email: None,
+                disabled_at: None,
+                disabled_reason: None,
+            })
+            .execute(&mut conn)
+            .unwrap();
+
+        let app_data = web::Data::new(models::AppData {
+            pool,
+            jwt_conf: auth::jwt::new("Secret string").token_storage(Box::new(MemoryStorage::new())),
+            mailer: None,
+            credential_policy: validating::CredentialPolicy::default(),
+        });
+        (app_data, path)
+    }
+
+    fn change_password_request(refresh_token: &str, password: &str, new_password: &str) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri("/change_password")
+            .insert_header((actix_web::http::header::AUTHORIZATION, format!("Bearer {}", refresh_token)))
+            .set_json(serde_json::json!({ "password": password, "new_password": new_password }))
+    }
+
+    #[actix_web::test]
+    async fn change_password() {
+        let (app_data, path) = app_data("change_password");
+        let app = test::init_service(App::new().app_data(app_data.clone()).configure(auth)).await;
+
+        let log_in_request = |password: &str| test::TestRequest::post()
+            .uri("/log_in?mode=token")
+            .set_json(serde_json::json!({ "username": "chef", "password": password }))
+            .to_request();
+        let current: serde_json::Value = test::call_and_read_body_json(&app, log_in_request(PASSWORD)).await;
+        let other: serde_json::Value = test::call_and_read_body_json(&app, log_in_request(PASSWORD)).await;
+        let access = current["access_token"].as_str().unwrap();
+        let current = current["refresh_token"].as_str().unwrap();
+        let other = other["refresh_token"].as_str().unwrap();
+        {
+            let mut conn = app_data.pool.get().unwrap();
+            db::api_tokens::create(&mut conn, "chef", "script", &[auth::Scope::RecipesWrite], None).unwrap();
+        }
+
+        // Only the refresh token can change the password
+        let res = test::call_service(&app, change_password_request(access, PASSWORD, NEW_PASSWORD).to_request()).await;
+        assert_eq!(res.status(), 401);
+
+        // Wrong current password
+        let res = test::call_service(&app, change_password_request(current, "Wrong1!", NEW_PASSWORD).to_request()).await;
+        assert_eq!(res.status(), 401);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["error"], "Wrong password");
+
+        // The new password has to follow the policy and differ from the current one
+        let res = test::call_service(&app, change_password_request(current, PASSWORD, "short").to_request()).await;
+        assert_eq!(res.status(), 400);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["violations"], serde_json::json!(["password_too_short", "password_no_uppercase", "password_no_special"]));
+
+        let res = test::call_service(&app, change_password_request(current, PASSWORD, PASSWORD).to_request()).await;
+        assert_eq!(res.status(), 400);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["violations"], serde_json::json!(["password_reused"]));
+
+        let res = test::call_service(&app, change_password_request(current, PASSWORD, NEW_PASSWORD).to_request()).await;
+        assert_eq!(res.status(), 200);
+
+        // Only the session that changed the password survives
+        let refresh_request = |token: &str| test::TestRequest::get()
+            .uri("/refresh?mode=token")
+            .insert_header((actix_web::http::header::AUTHORIZATION, format!("Bearer {}", token)))
+            .to_request();
+        assert_eq!(test::call_service(&app, refresh_request(other)).await.status(), 401);
+        assert_eq!(test::call_service(&app, refresh_request(current)).await.status(), 200);
+        {
+            let mut conn = app_data.pool.get().unwrap();
+            assert!(db::api_tokens::get_by_user(&mut conn, "chef").unwrap().is_empty());
+        }
+
+        assert_eq!(test::call_service(&app, log_in_request(PASSWORD)).await.status(), 401);
+        assert_eq!(test::call_service(&app, log_in_request(NEW_PASSWORD)).await.status(), 200);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[actix_web::test]
+    async fn refresh_uses_current_role() {
+        let (app_data, path) = app_data("refresh_uses_current_role");
+        let app = test::init_service(App::new().app_data(app_data.clone()).configure(auth)).await;
+
+        let log_in_request = test::TestRequest::post()
+            .uri("/log_in?mode=token")
+            .set_json(serde_json::json!({ "username": "chef", "password": PASSWORD }))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, log_in_request).await;
+        let refresh_token = body["refresh_token"].as_str().unwrap();
+
+        {
+            let mut conn = app_data.pool.get().unwrap();
+            diesel::update(users_dsl::users.find("chef"))
+                .set(users_dsl::role.eq(Role::Editor.to_string()))
+                .execute(&mut conn)
+                .unwrap();
+        }
+
+        let refresh_request = test::TestRequest::get()
+            .uri("/refresh?mode=token")
+            .insert_header((actix_web::http::header::AUTHORIZATION, format!("Bearer {}", refresh_token)))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, refresh_request).await;
+        for token in [&body["access_token"], &body["refresh_token"]] {
+            let jwt = app_data.jwt_conf.jwt_from_str(token.as_str().unwrap().to_owned());
+            assert_eq!(app_data.jwt_conf.validate(jwt).unwrap().get_role(), Role::Editor);
+        }
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[actix_web::test]
+    async fn tokens_without_family() {
+        let (app_data, path) = app_data("tokens_without_family");
+        let app = test::init_service(App::new().app_data(app_data.clone()).configure(auth)).await;
+        let jwt_conf = &app_data.jwt_conf;
+
+        // Issued before families existed
+        let legacy_token = |username: &str| {
+            let jwt = jwt_conf.new_jwt(JwtType::RefreshToken, username, Role::User, Some(""));
+            jwt_conf.register(jwt).unwrap().to_string()
+        };
+        let bearer = |token: &str| (actix_web::http::header::AUTHORIZATION, format!("Bearer {}", token));
+
+        // Every refreshed one starts its own family
+        let mut families = Vec::new();
+        for _ in 0..2 {
+            let refresh_request = test::TestRequest::get()
+                .uri("/refresh?mode=token")
+                .insert_header(bearer(&legacy_token("chef")))
+                .to_request();
+            let body: serde_json::Value = test::call_and_read_body_json(&app, refresh_request).await;
+            let jwt = jwt_conf.jwt_from_str(body["refresh_token"].as_str().unwrap().to_owned());
+            families.push(jwt_conf.validate(jwt).unwrap().get_family());
+        }
+        assert!(families.iter().all(|family| ! family.is_empty()));
+        assert_ne!(families[0], families[1]);
+
+        // Logging out revokes the token itself
+        let token = legacy_token("chef");
+        let log_out_request = test::TestRequest::get().uri("/log_out").insert_header(bearer(&token)).to_request();
+        assert_eq!(test::call_service(&app, log_out_request).await.status(), 200);
+        assert!(jwt_conf.validate(jwt_conf.jwt_from_str(token)).is_none());
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    /// The password alone doesn't reset the failures, so the second factor can't be guessed without the lockout
+    #[actix_web::test]
+    async fn lockout_covers_second_factor() {
+        let (app_data, path) = app_data("lockout_covers_second_factor");
+        let app = test::init_service(App::new().app_data(app_data.clone()).configure(auth)).await;
+        let mut conn = app_data.pool.get().unwrap();
+        db::totp::set_secret(&mut conn, "chef", &auth::totp::generate_secret()).unwrap();
+        db::totp::enable(&mut conn, "chef", 0, &[auth::hash_password("recovery")]).unwrap();
+
+        let log_in_request = || test::TestRequest::post()
+            .uri("/log_in?mode=token")
+            .set_json(serde_json::json!({ "username": "chef", "password": PASSWORD }))
+            .to_request();
+        let mfa_request = |mfa_token: &serde_json::Value, code: &str| test::TestRequest::post()
+            .uri("/log_in/mfa?mode=token")
+            .set_json(serde_json::json!({ "mfa_token": mfa_token, "code": code }))
+            .to_request();
+        let failed_count = |conn: &mut db::Conn| db::login_attempts::get(conn, "chef").unwrap().map(|val| val.failed_count);
+
+        db::login_attempts::record_failure(&mut conn, "chef").unwrap();
+        db::login_attempts::record_failure(&mut conn, "chef").unwrap();
+        let first: serde_json::Value = test::call_and_read_body_json(&app, log_in_request()).await;
+        let second: serde_json::Value = test::call_and_read_body_json(&app, log_in_request()).await;
+        assert_eq!(failed_count(&mut conn), Some(2));
+
+        // Locked out codes aren't even checked
+        db::login_attempts::record_failure(&mut conn, "chef").unwrap();
+        db::login_attempts::record_failure(&mut conn, "chef").unwrap();
+        let res = test::call_service(&app, mfa_request(&first["mfa_token"], "recovery")).await;
+        assert_eq!(res.status(), 429);
+
+        // Only a completed log in clears them
+        db::login_attempts::clear(&mut conn, "chef").unwrap();
+        db::login_attempts::record_failure(&mut conn, "chef").unwrap();
+        let res = test::call_service(&app, mfa_request(&second["mfa_token"], "recovery")).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(failed_count(&mut conn), None);
+
+        drop(conn);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[actix_web::test]
+    async fn reset_password_revokes_everything() {
+        let (app_data, path) = app_data("reset_password_revokes_everything");
+        let app = test::init_service(App::new().app_data(app_data.clone()).configure(auth)).await;
+        let mut conn = app_data.pool.get().unwrap();
+
+        db::api_tokens::create(&mut conn, "chef", "script", &[auth::Scope::RecipesWrite], None).unwrap();
+        let token = db::password_resets::create(&mut conn, "chef", chrono::Duration::minutes(30)).unwrap();
+
+        let reset_request = |token: &str, new_password: &str| test::TestRequest::post()
+            .uri("/reset_password")
+            .set_json(serde_json::json!({ "token": token, "new_password": new_password }))
+            .to_request();
+        assert_eq!(test::call_service(&app, reset_request(&token, NEW_PASSWORD)).await.status(), 200);
+
+        // The link and the personal access tokens stop working
+        assert_eq!(test::call_service(&app, reset_request(&token, "Another3#pw")).await.status(), 401);
+        assert!(db::api_tokens::get_by_user(&mut conn, "chef").unwrap().is_empty());
+
+        drop(conn);
+        let _ = std::fs::remove_file(path);
+    }
+
+    /// Nothing of a deleted user carries over to a new one with the same name
+    async fn delete_then_register_case(name: &str, foreign_keys: bool) {
+        let (app_data, path) = app_data(name);
+        let app = test::init_service(App::new().app_data(app_data.clone()).configure(auth)).await;
+        let mut conn = app_data.pool.get().unwrap();
+        db::key_value::set(&mut conn, "registration", "open").unwrap();
+
+        db::api_tokens::create(&mut conn, "chef", "script", &[auth::Scope::RecipesWrite], None).unwrap();
+        db::totp::set_secret(&mut conn, "chef", &auth::totp::generate_secret()).unwrap();
+        db::totp::enable(&mut conn, "chef", 0, &[auth::hash_password("recovery")]).unwrap();
+        let reset_token = db::password_resets::create(&mut conn, "chef", chrono::Duration::minutes(30)).unwrap();
+        db::invitations::create(&mut conn, Some("chef"), 1, chrono::Duration::days(1)).unwrap();
+        let log_in_request = test::TestRequest::post()
+            .uri("/log_in?mode=token")
+            .set_json(serde_json::json!({ "username": "chef", "password": PASSWORD }))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, log_in_request).await;
+        let mfa_token = body["mfa_token"].as_str().unwrap().to_owned();
+
+        if ! foreign_keys {
+            conn.batch_execute("PRAGMA foreign_keys = OFF").unwrap();
+        }
+        assert!(db::users::delete(&mut conn, "chef", None).unwrap());
+        let register_request = test::TestRequest::post()
+            .uri("/register")
+            .set_json(serde_json::json!({ "username": "chef", "password": NEW_PASSWORD }))
+            .to_request();
+        assert_eq!(test::call_service(&app, register_request).await.status(), 200);
+
+        assert!(db::api_tokens::get_by_user(&mut conn, "chef").unwrap().is_empty());
+        assert!(db::totp::get(&mut conn, "chef").unwrap().is_none());
+        assert!(db::invitations::get_all(&mut conn).unwrap().iter().all(|invitation| invitation.created_by.is_none()));
+        let reset_request = test::TestRequest::post()
+            .uri("/reset_password")
+            .set_json(serde_json::json!({ "token": reset_token, "new_password": "Another3#pw" }))
+            .to_request();
+        assert_eq!(test::call_service(&app, reset_request).await.status(), 401);
+
+        // The second factor of the old account doesn't log in to the new one
+        let mfa_request = test::TestRequest::post()
+            .uri("/log_in/mfa?mode=token")
+            .set_json(serde_json::json!({ "mfa_token": mfa_token, "code": "recovery" }))
+            .to_request();
+        assert_eq!(test::call_service(&app, mfa_request).await.status(), 401);
+
+        drop(conn);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[actix_web::test]
+    async fn delete_then_register() {
+        delete_then_register_case("delete_then_register", true).await;
+    }
+
+    /// Like with a system sqlite that doesn't enforce the foreign keys
+    #[actix_web::test]
+    async fn delete_then_register_without_foreign_keys() {
+        delete_then_register_case("delete_then_register_without_foreign_keys", false).await;
+    }
+
+    /// Whichever way the tokens are kept, refreshing ends the old access token
+    #[actix_web::test]
+    async fn refresh_invalidates_access_token() {
+        let (app_data, path) = app_data("refresh_invalidates_access_token");
+        let app = test::init_service(App::new().app_data(app_data.clone()).configure(auth)).await;
+        let jwt_conf = &app_data.jwt_conf;
+        let is_valid = |token: &str| jwt_conf.validate(jwt_conf.jwt_from_str(token.to_owned())).is_some();
+        let log_in_request = |mode: &str| test::TestRequest::post()
+            .uri(&format!("/log_in?mode={}", mode))
+            .set_json(serde_json::json!({ "username": "chef", "password": PASSWORD }))
+            .to_request();
+
+        let body: serde_json::Value = test::call_and_read_body_json(&app, log_in_request("token")).await;
+        let refresh_request = test::TestRequest::get()
+            .uri("/refresh?mode=token")
+            .insert_header((actix_web::http::header::AUTHORIZATION, format!("Bearer {}", body["refresh_token"].as_str().unwrap())))
+            .to_request();
+        let refreshed: serde_json::Value = test::call_and_read_body_json(&app, refresh_request).await;
+        assert!(! is_valid(body["access_token"].as_str().unwrap()));
+        assert!(is_valid(refreshed["access_token"].as_str().unwrap()));
+
+        let res = test::call_service(&app, log_in_request("cookie")).await;
+        let cookie = |res: &actix_web::dev::ServiceResponse, name: CookieName| res.response().cookies()
+            .find(|val| val.name() == name.to_string())
+            .unwrap()
+            .into_owned();
+        let refresh_request = |refresh_cookie| test::TestRequest::get()
+            .uri("/refresh")
+            .cookie(refresh_cookie)
+            .to_request();
+        let first = test::call_service(&app, refresh_request(cookie(&res, CookieName::RefreshToken))).await;
+        let second = test::call_service(&app, refresh_request(cookie(&first, CookieName::RefreshToken))).await;
+        assert!(! is_valid(cookie(&first, CookieName::AccessToken).value()));
+        assert!(is_valid(cookie(&second, CookieName::AccessToken).value()));
+
+        let _ = std::fs::remove_file(path);
+    }
+}
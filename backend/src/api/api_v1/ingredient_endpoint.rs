@@ -0,0 +1,131 @@
+use actix_web::{HttpRequest, HttpResponse, web};
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use serde::Deserialize;
+
+use super::db::prelude::*;
+use super::{db, models, validating};
+use super::audit::{Action, Event};
+use super::auth::guard::{AdminRole, Auth, IngredientsWriteScope};
+
+
+pub fn ingredients(cfg: &mut web::ServiceConfig) {
+    cfg
+        .service(get_ingredients)
+        .service(post_ingredient)
+        .service(patch_ingredient)
+        .service(delete_ingredient);
+}
+
+
+#[derive(Deserialize)]
+struct IngredientData {
+    name: String,
+}
+
+
+#[actix_web::get("")]
+async fn get_ingredients(
+    app_data: web::Data<models::AppData>,
+) -> HttpResponse {
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    match db::ingredients::get_all(&mut conn) {
+        Ok(val) => HttpResponse::Ok().json(val),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+
+#[actix_web::post("")]
+async fn post_ingredient(
+    req: HttpRequest,
+    auth: Auth<AdminRole, IngredientsWriteScope>,
+    app_data: web::Data<models::AppData>,
+    ingredient_data: web::Json<IngredientData>,
+) -> HttpResponse {
+    if ! validating::is_valid_ingredient_name(&ingredient_data.name) {
+        return HttpResponse::BadRequest().finish();
+    }
+
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    let query_result = diesel::insert_into(ingredients_dsl::ingredients)
+        .values(models::Ingredient {
+            name: ingredient_data.name.clone(),
+        })
+        .execute(&mut conn);
+
+    match query_result {
+        Ok(_) => {
+            Event::new(&req, Some(&auth.claims.get_username()), Action::IngredientCreated)
+                .target(&ingredient_data.name)
+                .record(&mut conn);
+            HttpResponse::Ok().finish()
+        },
+        Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => HttpResponse::Conflict().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+
+/// Renames an ingredient, recipes using it are updated as well
+#[actix_web::patch("/{ingredient_name}")]
+async fn patch_ingredient(
+    path: web::Path<String>,
+    req: HttpRequest,
+    auth: Auth<AdminRole, IngredientsWriteScope>,
+    app_data: web::Data<models::AppData>,
+    ingredient_data: web::Json<IngredientData>,
+) -> HttpResponse {
+    if ! validating::is_valid_ingredient_name(&ingredient_data.name) {
+        return HttpResponse::BadRequest().finish();
+    }
+
+    let ingredient_name = path.into_inner();
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    match db::ingredients::rename(&mut conn, &ingredient_name, &ingredient_data.name) {
+        Ok(0) => HttpResponse::NotFound().finish(),
+        Ok(_) => {
+            Event::new(&req, Some(&auth.claims.get_username()), Action::IngredientRenamed)
+                .target(format!("{} -> {}", ingredient_name, ingredient_data.name))
+                .record(&mut conn);
+            HttpResponse::Ok().finish()
+        },
+        Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => HttpResponse::Conflict().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+
+#[actix_web::delete("/{ingredient_name}")]
+async fn delete_ingredient(
+    path: web::Path<String>,
+    req: HttpRequest,
+    auth: Auth<AdminRole, IngredientsWriteScope>,
+    app_data: web::Data<models::AppData>,
+) -> HttpResponse {
+    let ingredient_name = path.into_inner();
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    // Don't leave recipes pointing to a missing ingredient
+    match db::ingredients::is_used(&mut conn, &ingredient_name) {
+        Ok(false) => {},
+        Ok(true) => return HttpResponse::Conflict().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    }
+
+    let query_result = diesel::delete(ingredients_dsl::ingredients.find(&ingredient_name))
+        .execute(&mut conn);
+
+    match query_result {
+        Ok(0) => HttpResponse::NotFound().finish(),
+        Ok(_) => {
+            Event::new(&req, Some(&auth.claims.get_username()), Action::IngredientDeleted)
+                .target(&ingredient_name)
+                .record(&mut conn);
+            HttpResponse::Ok().finish()
+        },
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
@@ -0,0 +1,92 @@
+use actix_web::{HttpRequest, HttpResponse, web};
+use serde::{Deserialize, Serialize};
+
+use super::{db, models};
+use super::audit::{Action, Event};
+use super::auth::guard::{AdminRole, Auth};
+
+
+pub fn invitations(cfg: &mut web::ServiceConfig) {
+    cfg
+        .service(get_invitations)
+        .service(post_invitation)
+        .service(delete_invitation);
+}
+
+
+#[derive(Deserialize)]
+struct NewInvitationData {
+    uses: i32,
+    valid_for_days: i64,
+}
+
+
+#[actix_web::get("")]
+async fn get_invitations(
+    _auth: Auth<AdminRole>,
+    app_data: web::Data<models::AppData>,
+) -> HttpResponse {
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    match db::invitations::get_all(&mut conn) {
+        Ok(val) => HttpResponse::Ok().json(val),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+
+#[actix_web::post("")]
+async fn post_invitation(
+    req: HttpRequest,
+    auth: Auth<AdminRole>,
+    app_data: web::Data<models::AppData>,
+    invitation_data: web::Json<NewInvitationData>,
+) -> HttpResponse {
+    // Data that will be returned if successful
+    #[derive(Serialize)]
+    struct ResponseData {
+        code: String,
+    }
+
+    if invitation_data.uses <= 0 || ! (1..=365).contains(&invitation_data.valid_for_days) {
+        return HttpResponse::BadRequest().finish();
+    }
+
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    let query_result = db::invitations::create(
+        &mut conn,
+        Some(&auth.claims.get_username()),
+        invitation_data.uses,
+        chrono::Duration::days(invitation_data.valid_for_days),
+    );
+
+    match query_result {
+        Ok(code) => {
+            Event::new(&req, Some(&auth.claims.get_username()), Action::InvitationCreated).record(&mut conn);
+            HttpResponse::Ok().json(ResponseData { code })
+        },
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+
+#[actix_web::delete("/{id}")]
+async fn delete_invitation(
+    path: web::Path<i32>,
+    req: HttpRequest,
+    auth: Auth<AdminRole>,
+    app_data: web::Data<models::AppData>,
+) -> HttpResponse {
+    let id = path.into_inner();
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    match db::invitations::revoke(&mut conn, id) {
+        Ok(0) => HttpResponse::NotFound().finish(),
+        Ok(_) => {
+            Event::new(&req, Some(&auth.claims.get_username()), Action::InvitationRevoked).target(id).record(&mut conn);
+            HttpResponse::Ok().finish()
+        },
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
@@ -0,0 +1,74 @@
+use actix_web::{HttpRequest, HttpResponse, web};
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Serialize;
+
+use super::{auth, db, models};
+use super::audit::{Action, Event};
+use super::auth::guard::{AdminRole, Auth};
+
+
+pub fn lockouts(cfg: &mut web::ServiceConfig) {
+    cfg
+        .service(get_lockouts)
+        .service(delete_lockout);
+}
+
+
+/// Lists the usernames with recent failed log ins
+#[actix_web::get("")]
+async fn get_lockouts(
+    _auth: Auth<AdminRole>,
+    app_data: web::Data<models::AppData>,
+) -> HttpResponse {
+    #[derive(Serialize)]
+    struct Lockout {
+        username: String,
+        failed_attempts: i32,
+        last_failed_at: DateTime<Utc>,
+        /// None if the user can try again right away
+        blocked_until: Option<DateTime<Utc>>,
+    }
+
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    let attempts = match db::login_attempts::get_all(&mut conn) {
+        Ok(val) => val,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    let now = Utc::now();
+    let response_data: Vec<Lockout> = attempts.into_iter()
+        .filter(|attempt| now.timestamp() - attempt.last_failed < auth::lockout::RESET_SECONDS)
+        .map(|attempt| Lockout {
+            blocked_until: auth::lockout::retry_after(Some(&attempt), now.timestamp())
+                .map(|val| now + chrono::Duration::seconds(val)),
+            username: attempt.username,
+            failed_attempts: attempt.failed_count,
+            last_failed_at: Utc.timestamp_opt(attempt.last_failed, 0).unwrap(),
+        })
+        .collect();
+
+    HttpResponse::Ok().json(response_data)
+}
+
+
+/// Unlocks the user and resets their failed log ins
+#[actix_web::delete("/{username}")]
+async fn delete_lockout(
+    path: web::Path<String>,
+    req: HttpRequest,
+    auth: Auth<AdminRole>,
+    app_data: web::Data<models::AppData>,
+) -> HttpResponse {
+    let username = path.into_inner();
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    match db::login_attempts::clear(&mut conn, &username) {
+        Ok(0) => HttpResponse::NotFound().finish(),
+        Ok(_) => {
+            Event::new(&req, Some(&auth.claims.get_username()), Action::UserUnlocked).target(&username).record(&mut conn);
+            HttpResponse::Ok().finish()
+        },
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
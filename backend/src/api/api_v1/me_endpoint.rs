@@ -1,33 +1,665 @@
-use actix_web::{HttpResponse, HttpRequest, web};
-use serde::Serialize;
+use actix_web::{HttpRequest, HttpResponse, web};
+use chrono::{DateTime, TimeZone, Utc};
+use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
 
-use super::models;
-use super::auth::CookieName;
+use super::{auth, db, models, validating};
+use super::audit::{Action, Event};
+use super::db::prelude::*;
+use super::auth::guard::{Auth, ProfileReadScope, UserRole};
+use super::auth::{Role, Scope};
 
 
 pub fn me(cfg: &mut web::ServiceConfig) {
     cfg
-        .service(get_me);
+        .service(get_me)
+        .service(delete_me)
+        .service(get_export)
+        .service(put_email)
+        .service(get_sessions)
+        .service(delete_sessions)
+        .service(delete_session)
+        .service(get_tokens)
+        .service(post_token)
+        .service(delete_token)
+        .service(get_2fa)
+        .service(post_2fa)
+        .service(confirm_2fa)
+        .service(delete_2fa);
 }
 
 
 #[actix_web::get("")]
 async fn get_me(
-    req: HttpRequest,
+    auth: Auth<UserRole, ProfileReadScope>,
     app_data: web::Data<models::AppData>,
 ) -> HttpResponse {
     // Data that will be returned if successful
     #[derive(Serialize)]
     struct ResponseData {
         username: String,
+        role: Role,
+        email: Option<String>,
     }
 
-    let jwt_conf = &app_data.jwt_conf;
-    let claims = super::check_access_token!(jwt_conf, req);
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    let email: Option<String> = match users_dsl::users
+        .select(users_dsl::email)
+        .find(auth.claims.get_username())
+        .first(&mut conn) {
+        Ok(val) => val,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
 
     let response_data = ResponseData {
-        username: claims.get_username(),
+        username: auth.claims.get_username(),
+        role: auth.claims.get_role(),
+        email,
+    };
+
+    HttpResponse::Ok().json(response_data)
+}
+
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum RecipesAction {
+    Transfer,
+    Delete,
+}
+
+#[derive(Deserialize)]
+struct DeleteAccountData {
+    password: String,
+    /// What happens to the recipes of the user
+    recipes: RecipesAction,
+    /// Needed when transferring the recipes
+    transfer_to: Option<String>,
+}
+
+/// Requires the password, so a stolen session can't delete the account
+#[actix_web::delete("")]
+async fn delete_me(
+    req: HttpRequest,
+    auth: Auth,
+    app_data: web::Data<models::AppData>,
+    delete_data: web::Json<DeleteAccountData>,
+) -> HttpResponse {
+    let username = auth.claims.get_username();
+
+    let transfer_to = match (&delete_data.recipes, &delete_data.transfer_to) {
+        (RecipesAction::Transfer, Some(val)) if *val != username => Some(val.as_str()),
+        (RecipesAction::Transfer, _) => return HttpResponse::BadRequest().finish(),
+        (RecipesAction::Delete, _) => None,
+    };
+
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    let pw_hash: String = match users_dsl::users
+        .select(users_dsl::password_hash)
+        .find(&username)
+        .first(&mut conn) {
+        Ok(val) => val,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    if ! auth::verify_password(&delete_data.password, &pw_hash) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    // The server doesn't start without an admin
+    match db::users::is_last_admin(&mut conn, &username) {
+        Ok(false) => {},
+        Ok(true) => return HttpResponse::Conflict().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    }
+
+    match db::users::delete(&mut conn, &username, transfer_to) {
+        Ok(true) => {},
+        Ok(false) => return HttpResponse::NotFound().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    }
+
+    Event::new(&req, Some(&username), Action::AccountDeleted).record(&mut conn);
+
+    // The memory token store isn't cleaned by the foreign keys
+    match app_data.jwt_conf.invalidate_sessions(&username, None) {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+
+/// All the data stored about the user, as a downloadable json file
+#[actix_web::get("/export")]
+async fn get_export(
+    req: HttpRequest,
+    auth: Auth,
+    app_data: web::Data<models::AppData>,
+) -> HttpResponse {
+    #[derive(Serialize)]
+    struct Profile {
+        username: String,
+        role: Role,
+        email: Option<String>,
+        two_factor: bool,
+    }
+
+    #[derive(Serialize)]
+    struct Recipe {
+        name: String,
+        instructions: Vec<String>,
+        ingredients: Vec<models::Ammount>,
+    }
+
+    #[derive(Serialize)]
+    struct Session {
+        issued_at: DateTime<Utc>,
+        expires_at: DateTime<Utc>,
+        user_agent: Option<String>,
+        ip: Option<String>,
+    }
+
+    #[derive(Serialize)]
+    struct ApiToken {
+        name: String,
+        scopes: Vec<String>,
+        created_at: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+    }
+
+    #[derive(Serialize)]
+    struct ResponseData {
+        exported_at: DateTime<Utc>,
+        profile: Profile,
+        recipes: Vec<Recipe>,
+        sessions: Vec<Session>,
+        api_tokens: Vec<ApiToken>,
+    }
+
+    let username = auth.claims.get_username();
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    let user: models::User = match users_dsl::users.find(&username).first(&mut conn) {
+        Ok(val) => val,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let two_factor = match db::totp::get(&mut conn, &username) {
+        Ok(val) => val.is_some_and(|val| val.enabled),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    let recipes: Vec<models::Recipe> = match recipes_dsl::recipes
+        .filter(recipes_dsl::owner.eq(&username))
+        .order(recipes_dsl::name.asc())
+        .load(&mut conn) {
+        Ok(val) => val,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let mut recipes_data = Vec::with_capacity(recipes.len());
+    for recipe in recipes {
+        let ingredients: Vec<models::Ammount> = match ammounts_dsl::ammounts
+            .select((ammounts_dsl::kind, ammounts_dsl::ammount, ammounts_dsl::unit))
+            .filter(ammounts_dsl::recipe.eq(&recipe.name))
+            .order(ammounts_dsl::id.asc())
+            .load(&mut conn) {
+            Ok(val) => val,
+            Err(_) => return HttpResponse::InternalServerError().finish(),
+        };
+        recipes_data.push(Recipe {
+            instructions: serde_json::from_str(&recipe.instructions).unwrap_or_default(),
+            name: recipe.name,
+            ingredients,
+        });
+    }
+
+    let sessions = match app_data.jwt_conf.get_sessions(&username) {
+        Ok(val) => val,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let api_tokens = match db::api_tokens::get_by_user(&mut conn, &username) {
+        Ok(val) => val,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    let response_data = ResponseData {
+        exported_at: Utc::now(),
+        profile: Profile {
+            username: user.username,
+            role: auth.claims.get_role(),
+            email: user.email,
+            two_factor,
+        },
+        recipes: recipes_data,
+        sessions: sessions.into_iter()
+            .map(|entry| Session {
+                issued_at: entry.issuing,
+                expires_at: entry.expiration,
+                user_agent: entry.client.user_agent,
+                ip: entry.client.ip,
+            })
+            .collect(),
+        api_tokens: api_tokens.into_iter()
+            .map(|token| ApiToken {
+                name: token.name,
+                scopes: token.scopes.split_whitespace().map(str::to_owned).collect(),
+                created_at: Utc.timestamp_opt(token.created_at, 0).unwrap(),
+                expires_at: token.expiration.map(|val| Utc.timestamp_opt(val, 0).unwrap()),
+            })
+            .collect(),
+    };
+
+    Event::new(&req, Some(&username), Action::DataExported).record(&mut conn);
+
+    HttpResponse::Ok()
+        .append_header((actix_web::http::header::CONTENT_DISPOSITION, "attachment; filename=\"cookbook_export.json\""))
+        .json(response_data)
+}
+
+
+#[derive(Deserialize)]
+struct EmailData {
+    password: String,
+    /// Null removes the email
+    email: Option<String>,
+}
+
+/// Requires the password, so a stolen session can't redirect the password reset links
+#[actix_web::put("/email")]
+async fn put_email(
+    req: HttpRequest,
+    auth: Auth,
+    app_data: web::Data<models::AppData>,
+    email_data: web::Json<EmailData>,
+) -> HttpResponse {
+    if email_data.email.as_deref().is_some_and(|val| ! validating::is_valid_email(val)) {
+        return HttpResponse::BadRequest().finish();
+    }
+
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    let pw_hash: String = match users_dsl::users
+        .select(users_dsl::password_hash)
+        .find(auth.claims.get_username())
+        .first(&mut conn) {
+        Ok(val) => val,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    if ! auth::verify_password(&email_data.password, &pw_hash) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let query_result = diesel::update(users_dsl::users.find(auth.claims.get_username()))
+        .set(users_dsl::email.eq(&email_data.email))
+        .execute(&mut conn);
+
+    match query_result {
+        Ok(_) => {
+            Event::new(&req, Some(&auth.claims.get_username()), Action::EmailChanged).record(&mut conn);
+            HttpResponse::Ok().finish()
+        },
+        Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => HttpResponse::Conflict().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+
+#[actix_web::get("/sessions")]
+async fn get_sessions(
+    auth: Auth,
+    app_data: web::Data<models::AppData>,
+) -> HttpResponse {
+    #[derive(Serialize)]
+    struct Session {
+        id: String,
+        issued_at: DateTime<Utc>,
+        expires_at: DateTime<Utc>,
+        user_agent: Option<String>,
+        ip: Option<String>,
+        /// The session making this request
+        current: bool,
+    }
+
+    let sessions = match app_data.jwt_conf.get_sessions(&auth.claims.get_username()) {
+        Ok(val) => val,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    let current_family = auth.claims.get_family();
+    let response_data: Vec<Session> = sessions.into_iter()
+        .filter_map(|entry| {
+            let id = entry.family?;
+            Some(Session {
+                current: id == current_family,
+                id,
+                issued_at: entry.issuing,
+                expires_at: entry.expiration,
+                user_agent: entry.client.user_agent,
+                ip: entry.client.ip,
+            })
+        })
+        .collect();
+
+    HttpResponse::Ok().json(response_data)
+}
+
+
+/// Logs out everywhere except the current session
+#[actix_web::delete("/sessions")]
+async fn delete_sessions(
+    req: HttpRequest,
+    auth: Auth,
+    app_data: web::Data<models::AppData>,
+) -> HttpResponse {
+    let jwt_conf = &app_data.jwt_conf;
+    let username = auth.claims.get_username();
+
+    if jwt_conf.invalidate_sessions(&username, Some(&auth.claims.get_family())).is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+    Event::new(&req, Some(&username), Action::SessionsRevoked).record(&mut conn);
+    HttpResponse::Ok().finish()
+}
+
+
+#[actix_web::delete("/sessions/{session_id}")]
+async fn delete_session(
+    path: web::Path<String>,
+    req: HttpRequest,
+    auth: Auth,
+    app_data: web::Data<models::AppData>,
+) -> HttpResponse {
+    let session_id = path.into_inner();
+    let jwt_conf = &app_data.jwt_conf;
+
+    // Only allow revoking sessions of the logged in user
+    let sessions = match jwt_conf.get_sessions(&auth.claims.get_username()) {
+        Ok(val) => val,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    if ! sessions.iter().any(|entry| entry.family.as_deref() == Some(session_id.as_str())) {
+        return HttpResponse::NotFound().finish();
+    }
+
+    if jwt_conf.invalidate_family(&session_id).is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+    Event::new(&req, Some(&auth.claims.get_username()), Action::SessionRevoked).target(&session_id).record(&mut conn);
+    HttpResponse::Ok().finish()
+}
+
+
+#[actix_web::get("/tokens")]
+async fn get_tokens(
+    auth: Auth,
+    app_data: web::Data<models::AppData>,
+) -> HttpResponse {
+    #[derive(Serialize)]
+    struct ApiToken {
+        id: i32,
+        name: String,
+        scopes: Vec<String>,
+        created_at: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+    }
+
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    let tokens = match db::api_tokens::get_by_user(&mut conn, &auth.claims.get_username()) {
+        Ok(val) => val,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
     };
 
+    let response_data: Vec<ApiToken> = tokens.into_iter()
+        .map(|token| ApiToken {
+            id: token.id,
+            name: token.name,
+            scopes: token.scopes.split_whitespace().map(str::to_owned).collect(),
+            created_at: Utc.timestamp_opt(token.created_at, 0).unwrap(),
+            expires_at: token.expiration.map(|val| Utc.timestamp_opt(val, 0).unwrap()),
+        })
+        .collect();
+
     HttpResponse::Ok().json(response_data)
 }
+
+
+#[derive(Deserialize)]
+struct NewTokenData {
+    name: String,
+    scopes: Vec<Scope>,
+    /// The token never expires if not specified
+    expires_in_days: Option<i64>,
+}
+
+/// Only logged in sessions can create tokens, so a leaked token can't be used to make more of them
+#[actix_web::post("/tokens")]
+async fn post_token(
+    req: HttpRequest,
+    auth: Auth,
+    app_data: web::Data<models::AppData>,
+    token_data: web::Json<NewTokenData>,
+) -> HttpResponse {
+    // Data that will be returned if successful
+    #[derive(Serialize)]
+    struct ResponseData {
+        id: i32,
+        token: String,
+    }
+
+    let name = token_data.name.trim();
+    if name.is_empty() || 63 < name.len() || token_data.scopes.is_empty() {
+        return HttpResponse::BadRequest().finish();
+    }
+    let expiration = match token_data.expires_in_days {
+        Some(days) if (1..=365).contains(&days) => Some(Utc::now() + chrono::Duration::days(days)),
+        Some(_) => return HttpResponse::BadRequest().finish(),
+        None => None,
+    };
+
+    let mut scopes: Vec<Scope> = Vec::with_capacity(token_data.scopes.len());
+    for scope in &token_data.scopes {
+        if ! scopes.contains(scope) {
+            scopes.push(*scope);
+        }
+    }
+
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    match db::api_tokens::create(&mut conn, &auth.claims.get_username(), name, &scopes, expiration) {
+        Ok((id, token)) => {
+            Event::new(&req, Some(&auth.claims.get_username()), Action::TokenCreated).target(id).record(&mut conn);
+            HttpResponse::Ok().json(ResponseData { id, token })
+        },
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+
+#[actix_web::delete("/tokens/{id}")]
+async fn delete_token(
+    path: web::Path<i32>,
+    req: HttpRequest,
+    auth: Auth,
+    app_data: web::Data<models::AppData>,
+) -> HttpResponse {
+    let id = path.into_inner();
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    match db::api_tokens::revoke(&mut conn, &auth.claims.get_username(), id) {
+        Ok(0) => HttpResponse::NotFound().finish(),
+        Ok(_) => {
+            Event::new(&req, Some(&auth.claims.get_username()), Action::TokenRevoked).target(id).record(&mut conn);
+            HttpResponse::Ok().finish()
+        },
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+
+/// Number of recovery codes generated when two-factor authentication gets enabled
+const RECOVERY_CODE_COUNT: usize = 10;
+
+#[actix_web::get("/2fa")]
+async fn get_2fa(
+    auth: Auth,
+    app_data: web::Data<models::AppData>,
+) -> HttpResponse {
+    #[derive(Serialize)]
+    struct ResponseData {
+        enabled: bool,
+        recovery_codes_left: i64,
+    }
+
+    let username = auth.claims.get_username();
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    let enabled = match db::totp::get(&mut conn, &username) {
+        Ok(val) => val.is_some_and(|val| val.enabled),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let recovery_codes_left = match db::totp::count_recovery_codes(&mut conn, &username) {
+        Ok(val) => val,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    HttpResponse::Ok().json(ResponseData { enabled, recovery_codes_left })
+}
+
+
+/// Starts the enrollment, it has to be confirmed with a code before it takes effect
+#[actix_web::post("/2fa")]
+async fn post_2fa(
+    auth: Auth,
+    app_data: web::Data<models::AppData>,
+) -> HttpResponse {
+    // Data that will be returned if successful
+    #[derive(Serialize)]
+    struct ResponseData {
+        secret: String,
+        otpauth_url: String,
+    }
+
+    let username = auth.claims.get_username();
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    match db::totp::get(&mut conn, &username) {
+        Ok(Some(val)) if val.enabled => return HttpResponse::Conflict().finish(),
+        Ok(_) => (),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    }
+
+    let secret = auth::totp::generate_secret();
+    if db::totp::set_secret(&mut conn, &username, &secret).is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    HttpResponse::Ok().json(ResponseData {
+        otpauth_url: auth::totp::otpauth_url(&secret, &username),
+        secret,
+    })
+}
+
+
+#[derive(Deserialize)]
+struct ConfirmData {
+    code: String,
+}
+
+#[actix_web::post("/2fa/confirm")]
+async fn confirm_2fa(
+    req: HttpRequest,
+    auth: Auth,
+    app_data: web::Data<models::AppData>,
+    confirm_data: web::Json<ConfirmData>,
+) -> HttpResponse {
+    // Data that will be returned if successful
+    #[derive(Serialize)]
+    struct ResponseData {
+        /// Shown only once, only their hashes get stored
+        recovery_codes: Vec<String>,
+    }
+
+    let username = auth.claims.get_username();
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    // There has to be an enrollment waiting for confirmation
+    let totp = match db::totp::get(&mut conn, &username) {
+        Ok(Some(val)) if ! val.enabled => val,
+        Ok(Some(_)) => return HttpResponse::Conflict().finish(),
+        Ok(None) => return HttpResponse::BadRequest().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let step = match auth::totp::verify(&totp.secret, &confirm_data.code, Utc::now().timestamp(), totp.last_step) {
+        Some(val) => val,
+        None => return HttpResponse::BadRequest().finish(),
+    };
+
+    let recovery_codes: Vec<String> = {
+        let mut rng = rand::thread_rng();
+        (0..RECOVERY_CODE_COUNT)
+            .map(|_| {
+                let code: String = (0..8)
+                    .map(|_| rng.sample(rand::distributions::Alphanumeric) as char)
+                    .collect::<String>()
+                    .to_lowercase();
+                format!("{}-{}", &code[..4], &code[4..])
+            })
+            .collect()
+    };
+    let hashes: Vec<String> = recovery_codes.iter()
+        .map(|code| auth::hash_password(code))
+        .collect();
+
+    match db::totp::enable(&mut conn, &username, step, &hashes) {
+        Ok(_) => {
+            Event::new(&req, Some(&username), Action::TwoFactorEnabled).record(&mut conn);
+            HttpResponse::Ok().json(ResponseData { recovery_codes })
+        },
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+
+#[derive(Deserialize)]
+struct DisableData {
+    password: String,
+}
+
+/// Requires the password, so a stolen session can't remove the second factor
+#[actix_web::delete("/2fa")]
+async fn delete_2fa(
+    req: HttpRequest,
+    auth: Auth,
+    app_data: web::Data<models::AppData>,
+    disable_data: web::Json<DisableData>,
+) -> HttpResponse {
+    let username = auth.claims.get_username();
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    let pw_hash: String = match users_dsl::users
+        .select(users_dsl::password_hash)
+        .find(&username)
+        .first(&mut conn) {
+        Ok(val) => val,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    if ! auth::verify_password(&disable_data.password, &pw_hash) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    match db::totp::remove(&mut conn, &username) {
+        Ok(0) => HttpResponse::NotFound().finish(),
+        Ok(_) => {
+            Event::new(&req, Some(&username), Action::TwoFactorDisabled).record(&mut conn);
+            HttpResponse::Ok().finish()
+        },
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
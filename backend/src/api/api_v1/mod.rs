@@ -1,5 +1,6 @@
 #[allow(unused_imports)]
-use crate::{auth, db, macros, models, schema, unwrap_pretty, validating};
+use crate::{audit, auth, db, macros, models, schema, unwrap_pretty, validating};
+use crate::rate_limit::RateLimits;
 use actix_web::web;
 
 // Macros to use inside of this module
@@ -13,36 +14,51 @@ mod macro_mod {
         };
     }
     pub(crate) use get_conn;
-    
-    macro_rules! check_access_token {
-        ($jwt_conf:expr, $req:expr) => {{
-            // Try to get the access token
-            let access_token = match $req.cookie(&CookieName::AccessToken.to_string()) {
-                Some(val) => val.value().to_string(),
-                None => return HttpResponse::Unauthorized().finish(),
-            };
-            // Validate and deserialize it
-            let jwt = $jwt_conf.jwt_from_str(access_token);
-            match $jwt_conf.validate(jwt) {
-                Some(val) => val,
-                None => return HttpResponse::Unauthorized().finish(),
-            }
-        }};
-    }
-    pub(crate) use check_access_token;
 }
 use macro_mod::*;
 
 
 
 
+mod audit_endpoint;
 mod auth_endpoint;
+mod ingredient_endpoint;
+mod invitation_endpoint;
+mod lockout_endpoint;
 mod me_endpoint;
+mod recipe_endpoint;
+mod user_endpoint;
 
-pub fn api_v1(cfg: &mut web::ServiceConfig) {
+pub fn api_v1(cfg: &mut web::ServiceConfig, rate_limits: &RateLimits) {
     cfg
-        .service(web::scope("/auth").configure(auth_endpoint::auth))
-        .service(web::scope("/me").configure(me_endpoint::me));
+        .service(web::scope("/auth")
+            .wrap(rate_limits.limiter("auth"))
+            .configure(auth_endpoint::auth))
+        .service(web::scope("/me")
+            .wrap(rate_limits.split_limiter("reads", "writes"))
+            .configure(me_endpoint::me))
+        .service(web::scope("/recipes")
+            .wrap(rate_limits.split_limiter("reads", "writes"))
+            .configure(recipe_endpoint::recipes))
+        // Searching is a read, even though it's a post
+        .service(web::scope("/recipes_by_ingredients")
+            .wrap(rate_limits.limiter("reads"))
+            .configure(recipe_endpoint::recipes_by_ingredients))
+        .service(web::scope("/ingredients")
+            .wrap(rate_limits.split_limiter("reads", "writes"))
+            .configure(ingredient_endpoint::ingredients))
+        .service(web::scope("/admin/invitations")
+            .wrap(rate_limits.split_limiter("reads", "writes"))
+            .configure(invitation_endpoint::invitations))
+        .service(web::scope("/admin/lockouts")
+            .wrap(rate_limits.split_limiter("reads", "writes"))
+            .configure(lockout_endpoint::lockouts))
+        .service(web::scope("/admin/users")
+            .wrap(rate_limits.split_limiter("reads", "writes"))
+            .configure(user_endpoint::users))
+        .service(web::scope("/admin/audit_events")
+            .wrap(rate_limits.limiter("reads"))
+            .configure(audit_endpoint::audit_events));
 
 
 
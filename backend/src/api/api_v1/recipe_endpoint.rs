@@ -0,0 +1,575 @@
+use actix_web::{HttpResponse, web};
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+use super::db::prelude::*;
+use super::{db, models, validating};
+use super::auth::guard::{Auth, RecipesWriteScope, RequiredRole, RequiredScope, UserRole};
+use super::auth::{Role, Scope};
+
+
+pub fn recipes(cfg: &mut web::ServiceConfig) {
+    cfg
+        .service(get_recipes)
+        .service(get_recipe)
+        .service(post_recipe)
+        .service(put_recipe)
+        .service(delete_recipe);
+}
+
+pub fn recipes_by_ingredients(cfg: &mut web::ServiceConfig) {
+    cfg
+        .service(search_by_ingredients);
+}
+
+
+#[derive(Serialize)]
+struct Recipe {
+    name: String,
+}
+
+#[derive(Serialize)]
+struct RecipeFull {
+    name: String,
+    can_update: bool,
+    instructions: Vec<String>,
+    ingredients: Vec<models::Ammount>,
+}
+
+#[derive(Serialize)]
+struct RecipeMatch {
+    name: String,
+    missing: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct NewRecipeData {
+    instructions: Vec<String>,
+    ingredients: Vec<models::Ammount>,
+}
+
+#[derive(Deserialize)]
+struct UpdateRecipeData {
+    name: Option<String>,
+    instructions: Option<Vec<String>>,
+    ingredients: Option<Vec<models::Ammount>>,
+}
+
+
+/// Owners can modify their own recipes, editors and admins can modify all of them
+fn can_modify<R: RequiredRole, S: RequiredScope>(auth: &Auth<R, S>, owner: &str) -> bool {
+    auth.claims.get_username() == owner || Role::Editor <= auth.claims.get_role()
+}
+
+/// Checks if every ingredient kind is present in the ingredients table
+fn ingredients_exist(conn: &mut db::Conn, ingredients: &[models::Ammount]) -> Result<bool, DieselError> {
+    let kinds: Vec<&str> = ingredients.iter().map(|val| val.kind.as_str()).collect();
+    let found: i64 = ingredients_dsl::ingredients
+        .filter(ingredients_dsl::name.eq_any(&kinds))
+        .count()
+        .get_result(conn)?;
+
+    // Duplicates in the request would make the counts differ
+    let mut unique_kinds = kinds.clone();
+    unique_kinds.sort_unstable();
+    unique_kinds.dedup();
+    Ok(found as usize == unique_kinds.len())
+}
+
+/// Replaces all the ammounts of a recipe with the new ones
+fn set_ammounts(conn: &mut db::Conn, recipe: &str, ingredients: &[models::Ammount]) -> Result<(), DieselError> {
+    diesel::delete(ammounts_dsl::ammounts.filter(ammounts_dsl::recipe.eq(recipe)))
+        .execute(conn)?;
+
+    let new_ammounts: Vec<models::AmmountInsertable> = ingredients
+        .iter()
+        .map(|val| models::AmmountInsertable {
+            recipe: recipe.to_owned(),
+            kind: val.kind.clone(),
+            ammount: val.ammount,
+            unit: val.unit.clone(),
+        })
+        .collect();
+
+    diesel::insert_into(ammounts_dsl::ammounts)
+        .values(&new_ammounts)
+        .execute(conn)?;
+    Ok(())
+}
+
+
+#[actix_web::get("")]
+async fn get_recipes(
+    app_data: web::Data<models::AppData>,
+) -> HttpResponse {
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    let query_result = recipes_dsl::recipes
+        .select(recipes_dsl::name)
+        .order(recipes_dsl::name.asc())
+        .load::<String>(&mut conn);
+
+    match query_result {
+        Ok(names) => HttpResponse::Ok().json(
+            names.into_iter().map(|name| Recipe { name }).collect::<Vec<_>>()),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+
+#[actix_web::get("/{recipe_name}")]
+async fn get_recipe(
+    path: web::Path<String>,
+    // Only used for can_update, so tokens that can't update aren't recognized
+    auth: Option<Auth<UserRole, RecipesWriteScope>>,
+    app_data: web::Data<models::AppData>,
+) -> HttpResponse {
+    let recipe_name = path.into_inner();
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    let recipe: models::Recipe = match recipes_dsl::recipes
+        .find(&recipe_name)
+        .first(&mut conn)
+    {
+        Ok(val) => val,
+        Err(DieselError::NotFound) => return HttpResponse::NotFound().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    let ingredients: Vec<models::Ammount> = match ammounts_dsl::ammounts
+        .select((ammounts_dsl::kind, ammounts_dsl::ammount, ammounts_dsl::unit))
+        .filter(ammounts_dsl::recipe.eq(&recipe_name))
+        .order(ammounts_dsl::id.asc())
+        .load(&mut conn)
+    {
+        Ok(val) => val,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    let instructions: Vec<String> = match serde_json::from_str(&recipe.instructions) {
+        Ok(val) => val,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    // Not being logged in isn't an error here, the user just can't update anything
+    let can_update = match auth {
+        Some(auth) => can_modify(&auth, &recipe.owner) && auth.has_scope(Scope::RecipesWrite),
+        None => false,
+    };
+
+    HttpResponse::Ok().json(RecipeFull {
+        name: recipe.name,
+        can_update,
+        instructions,
+        ingredients,
+    })
+}
+
+
+#[actix_web::post("/{recipe_name}")]
+async fn post_recipe(
+    path: web::Path<String>,
+    auth: Auth<UserRole, RecipesWriteScope>,
+    app_data: web::Data<models::AppData>,
+    recipe_data: web::Json<NewRecipeData>,
+) -> HttpResponse {
+    let recipe_name = path.into_inner();
+    if ! validating::is_valid_recipe_name(&recipe_name) {
+        return HttpResponse::BadRequest().finish();
+    }
+
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    match ingredients_exist(&mut conn, &recipe_data.ingredients) {
+        Ok(true) => {},
+        Ok(false) => return HttpResponse::BadRequest().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    }
+
+    let new_recipe = models::Recipe {
+        name: recipe_name.clone(),
+        owner: auth.claims.get_username(),
+        instructions: serde_json::to_string(&recipe_data.instructions).unwrap(),
+    };
+
+    // Insert the recipe and its ingredients all at once
+    let query_result = conn.transaction::<_, DieselError, _>(|conn| {
+        diesel::insert_into(recipes_dsl::recipes)
+            .values(&new_recipe)
+            .execute(conn)?;
+        set_ammounts(conn, &recipe_name, &recipe_data.ingredients)
+    });
+
+    match query_result {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => HttpResponse::Conflict().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+
+#[actix_web::put("/{recipe_name}")]
+async fn put_recipe(
+    path: web::Path<String>,
+    auth: Auth<UserRole, RecipesWriteScope>,
+    app_data: web::Data<models::AppData>,
+    recipe_data: web::Json<UpdateRecipeData>,
+) -> HttpResponse {
+    let recipe_name = path.into_inner();
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    // Check if the recipe exists and belongs to the user
+    let owner: String = match recipes_dsl::recipes
+        .select(recipes_dsl::owner)
+        .find(&recipe_name)
+        .first(&mut conn)
+    {
+        Ok(val) => val,
+        Err(DieselError::NotFound) => return HttpResponse::NotFound().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    if ! can_modify(&auth, &owner) {
+        return HttpResponse::Forbidden().finish();
+    }
+
+    // Validate the new data before touching anything
+    if let Some(new_name) = &recipe_data.name {
+        if ! validating::is_valid_recipe_name(new_name) {
+            return HttpResponse::BadRequest().finish();
+        }
+    }
+    if let Some(ingredients) = &recipe_data.ingredients {
+        match ingredients_exist(&mut conn, ingredients) {
+            Ok(true) => {},
+            Ok(false) => return HttpResponse::BadRequest().finish(),
+            Err(_) => return HttpResponse::InternalServerError().finish(),
+        }
+    }
+
+    let query_result = conn.transaction::<_, DieselError, _>(|conn| {
+        let mut current_name = recipe_name.clone();
+
+        if let Some(new_name) = &recipe_data.name {
+            if *new_name != current_name {
+                // The ammounts point to the old name until they are moved, so check the keys on commit
+                diesel::sql_query("PRAGMA defer_foreign_keys = ON").execute(conn)?;
+                diesel::update(recipes_dsl::recipes.find(&current_name))
+                    .set(recipes_dsl::name.eq(new_name))
+                    .execute(conn)?;
+                diesel::update(ammounts_dsl::ammounts.filter(ammounts_dsl::recipe.eq(&current_name)))
+                    .set(ammounts_dsl::recipe.eq(new_name))
+                    .execute(conn)?;
+                current_name = new_name.clone();
+            }
+        }
+
+        if let Some(instructions) = &recipe_data.instructions {
+            diesel::update(recipes_dsl::recipes.find(&current_name))
+                .set(recipes_dsl::instructions.eq(serde_json::to_string(instructions).unwrap()))
+                .execute(conn)?;
+        }
+
+        if let Some(ingredients) = &recipe_data.ingredients {
+            set_ammounts(conn, &current_name, ingredients)?;
+        }
+
+        Ok(())
+    });
+
+    match query_result {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => HttpResponse::Conflict().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+
+#[actix_web::delete("/{recipe_name}")]
+async fn delete_recipe(
+    path: web::Path<String>,
+    auth: Auth<UserRole, RecipesWriteScope>,
+    app_data: web::Data<models::AppData>,
+) -> HttpResponse {
+    let recipe_name = path.into_inner();
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    // Check if the recipe exists and belongs to the user
+    let owner: String = match recipes_dsl::recipes
+        .select(recipes_dsl::owner)
+        .find(&recipe_name)
+        .first(&mut conn)
+    {
+        Ok(val) => val,
+        Err(DieselError::NotFound) => return HttpResponse::NotFound().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    if ! can_modify(&auth, &owner) {
+        return HttpResponse::Forbidden().finish();
+    }
+
+    let query_result = conn.transaction::<_, DieselError, _>(|conn| {
+        diesel::delete(ammounts_dsl::ammounts.filter(ammounts_dsl::recipe.eq(&recipe_name)))
+            .execute(conn)?;
+        diesel::delete(recipes_dsl::recipes.find(&recipe_name))
+            .execute(conn)?;
+        Ok(())
+    });
+
+    match query_result {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+
+/// Responds with every recipe that uses at least one of the specified ingredients.
+/// Recipes are ordered by the number of missing ingredients, the ones that can be cooked right away go first
+#[actix_web::post("")]
+async fn search_by_ingredients(
+    app_data: web::Data<models::AppData>,
+    available: web::Json<Vec<String>>,
+) -> HttpResponse {
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    let query_result = ammounts_dsl::ammounts
+        .inner_join(ingredients_dsl::ingredients)
+        .select((ammounts_dsl::recipe, ingredients_dsl::name))
+        .load::<(String, String)>(&mut conn);
+
+    let rows = match query_result {
+        Ok(val) => val,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    let available: HashSet<&str> = available.iter().map(String::as_str).collect();
+
+    // Group the needed ingredients by recipe, keeping track of which ones are missing
+    let mut recipes: BTreeMap<String, (bool, Vec<String>)> = BTreeMap::new();
+    for (recipe, ingredient) in rows {
+        let (uses_available, missing) = recipes.entry(recipe).or_default();
+        if available.contains(ingredient.as_str()) {
+            *uses_available = true;
+        } else if ! missing.contains(&ingredient) {
+            missing.push(ingredient);
+        }
+    }
+
+    let mut matches: Vec<RecipeMatch> = recipes
+        .into_iter()
+        .filter(|(_, (uses_available, _))| *uses_available)
+        .map(|(name, (_, mut missing))| {
+            missing.sort_unstable();
+            RecipeMatch { name, missing }
+        })
+        .collect();
+    // Stable sort, so recipes with the same coverage stay in alphabetical order
+    matches.sort_by_key(|val| val.missing.len());
+
+    HttpResponse::Ok().json(matches)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+    use crate::auth::{self, jwt::JwtType, token_storage::MemoryStorage};
+
+    /// A fresh database with a few users and ingredients
+    fn app_data(name: &str) -> (web::Data<models::AppData>, std::path::PathBuf) {
+        let (pool, path) = db::test_pool(name);
+        let mut conn = pool.get().unwrap();
+        for (username, role) in [("chef", Role::User), ("cook", Role::User), ("editor", Role::Editor)] {
+            diesel::insert_into(users_dsl::users)
+                .values(models::User {
+                    username: username.to_owned(),
+                    // Nobody logs in, the tokens are made directly
+                    password_hash: String::new(),
+                    role: role.to_string(),
+                    email: None,
+                    disabled_at: None,
+                    disabled_reason: None,
+                })
+                .execute(&mut conn)
+                .unwrap();
+        }
+        for ingredient in ["eggs", "flour", "milk", "sugar"] {
+            diesel::insert_into(ingredients_dsl::ingredients)
+                .values(models::Ingredient { name: ingredient.to_owned() })
+                .execute(&mut conn)
+                .unwrap();
+        }
+
+        let app_data = web::Data::new(models::AppData {
+            pool,
+            jwt_conf: auth::jwt::new("Secret string").token_storage(Box::new(MemoryStorage::new())),
+            mailer: None,
+            credential_policy: validating::CredentialPolicy::default(),
+        });
+        (app_data, path)
+    }
+
+    /// The Authorization header with a fresh access token, the guard looks up the role itself
+    fn bearer(app_data: &models::AppData, username: &str) -> (actix_web::http::header::HeaderName, String) {
+        let jwt = app_data.jwt_conf.new_jwt(JwtType::AccessToken, username, Role::User, None);
+        let token = app_data.jwt_conf.register(jwt).unwrap().to_string();
+        (actix_web::http::header::AUTHORIZATION, format!("Bearer {}", token))
+    }
+
+    fn post_request(app_data: &models::AppData, username: &str, name: &str, ingredients: &[&str]) -> test::TestRequest {
+        let ingredients: Vec<serde_json::Value> = ingredients.iter()
+            .map(|kind| serde_json::json!({ "kind": kind, "ammount": 1.0, "unit": "cup" }))
+            .collect();
+        test::TestRequest::post()
+            .uri(&format!("/{}", name))
+            .insert_header(bearer(app_data, username))
+            .set_json(serde_json::json!({ "instructions": ["Mix it"], "ingredients": ingredients }))
+    }
+
+    fn kinds(recipe: &serde_json::Value) -> Vec<&str> {
+        recipe["ingredients"].as_array().unwrap().iter().map(|val| val["kind"].as_str().unwrap()).collect()
+    }
+
+    /// The ammounts reference both names, so the renames have to get past the enforced foreign keys
+    #[actix_web::test]
+    async fn rename_keeps_ammounts() {
+        let (app_data, path) = app_data("rename_keeps_ammounts");
+        let app = test::init_service(App::new().app_data(app_data.clone()).configure(recipes)).await;
+
+        let res = test::call_service(&app, post_request(&app_data, "chef", "Pancakes", &["flour", "milk"]).to_request()).await;
+        assert_eq!(res.status(), 200);
+
+        let put_request = test::TestRequest::put()
+            .uri("/Pancakes")
+            .insert_header(bearer(&app_data, "chef"))
+            .set_json(serde_json::json!({ "name": "Crepes" }))
+            .to_request();
+        assert_eq!(test::call_service(&app, put_request).await.status(), 200);
+        {
+            let mut conn = app_data.pool.get().unwrap();
+            assert_eq!(db::ingredients::rename(&mut conn, "milk", "oat milk").unwrap(), 1);
+        }
+
+        let recipe: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/Crepes").to_request()).await;
+        assert_eq!(kinds(&recipe), ["flour", "oat milk"]);
+        let res = test::call_service(&app, test::TestRequest::get().uri("/Pancakes").to_request()).await;
+        assert_eq!(res.status(), 404);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[actix_web::test]
+    async fn create_and_read() {
+        let (app_data, path) = app_data("create_and_read");
+        let app = test::init_service(App::new().app_data(app_data.clone()).configure(recipes)).await;
+
+        let res = test::call_service(&app, post_request(&app_data, "chef", "Pancakes", &["flour", "milk", "eggs"]).to_request()).await;
+        assert_eq!(res.status(), 200);
+        let res = test::call_service(&app, post_request(&app_data, "cook", "Pancakes", &["flour"]).to_request()).await;
+        assert_eq!(res.status(), 409);
+        // Unknown ingredients
+        let res = test::call_service(&app, post_request(&app_data, "chef", "Omelette", &["eggs", "ham"]).to_request()).await;
+        assert_eq!(res.status(), 400);
+
+        // Only the owner and the editors can update it
+        let get_request = |username: Option<&str>| {
+            let request = test::TestRequest::get().uri("/Pancakes");
+            match username {
+                Some(username) => request.insert_header(bearer(&app_data, username)),
+                None => request,
+            }.to_request()
+        };
+        for (username, can_update) in [(Some("chef"), true), (Some("editor"), true), (Some("cook"), false), (None, false)] {
+            let recipe: serde_json::Value = test::call_and_read_body_json(&app, get_request(username)).await;
+            assert_eq!(recipe["can_update"], can_update, "{:?}", username);
+            assert_eq!(recipe["instructions"], serde_json::json!(["Mix it"]));
+            assert_eq!(kinds(&recipe), ["flour", "milk", "eggs"]);
+        }
+
+        let res = test::call_service(&app, test::TestRequest::get().uri("/Omelette").to_request()).await;
+        assert_eq!(res.status(), 404);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[actix_web::test]
+    async fn only_owner_modifies() {
+        let (app_data, path) = app_data("only_owner_modifies");
+        let app = test::init_service(App::new().app_data(app_data.clone()).configure(recipes)).await;
+        let res = test::call_service(&app, post_request(&app_data, "chef", "Pancakes", &["flour", "milk"]).to_request()).await;
+        assert_eq!(res.status(), 200);
+
+        let put_request = |username: &str, recipe: &str| test::TestRequest::put()
+            .uri(&format!("/{}", recipe))
+            .insert_header(bearer(&app_data, username))
+            .set_json(serde_json::json!({ "instructions": ["Mix it", "Fry it"] }))
+            .to_request();
+        let delete_request = |username: &str, recipe: &str| test::TestRequest::delete()
+            .uri(&format!("/{}", recipe))
+            .insert_header(bearer(&app_data, username))
+            .to_request();
+
+        assert_eq!(test::call_service(&app, put_request("cook", "Pancakes")).await.status(), 403);
+        assert_eq!(test::call_service(&app, delete_request("cook", "Pancakes")).await.status(), 403);
+        assert_eq!(test::call_service(&app, put_request("chef", "Omelette")).await.status(), 404);
+        assert_eq!(test::call_service(&app, delete_request("chef", "Omelette")).await.status(), 404);
+
+        // Renaming onto another recipe is a conflict
+        let res = test::call_service(&app, post_request(&app_data, "chef", "Waffles", &["flour"]).to_request()).await;
+        assert_eq!(res.status(), 200);
+        let rename_request = test::TestRequest::put()
+            .uri("/Waffles")
+            .insert_header(bearer(&app_data, "chef"))
+            .set_json(serde_json::json!({ "name": "Pancakes" }))
+            .to_request();
+        assert_eq!(test::call_service(&app, rename_request).await.status(), 409);
+
+        assert_eq!(test::call_service(&app, put_request("chef", "Pancakes")).await.status(), 200);
+        let recipe: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/Pancakes").to_request()).await;
+        assert_eq!(recipe["instructions"], serde_json::json!(["Mix it", "Fry it"]));
+        assert_eq!(kinds(&recipe), ["flour", "milk"]);
+
+        assert_eq!(test::call_service(&app, delete_request("chef", "Pancakes")).await.status(), 200);
+        let res = test::call_service(&app, test::TestRequest::get().uri("/Pancakes").to_request()).await;
+        assert_eq!(res.status(), 404);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[actix_web::test]
+    async fn search_ranking() {
+        let (app_data, path) = app_data("search_ranking");
+        let app = test::init_service(App::new().app_data(app_data.clone()).configure(recipes)).await;
+        for (name, ingredients) in [
+            ("Cookies", &["sugar", "milk", "flour"][..]),
+            ("Pancakes", &["flour", "milk", "eggs"]),
+            ("Omelette", &["eggs"]),
+            ("Crepes", &["flour", "eggs"]),
+            ("Milkshake", &["milk", "sugar"]),
+        ] {
+            let res = test::call_service(&app, post_request(&app_data, "chef", name, ingredients).to_request()).await;
+            assert_eq!(res.status(), 200);
+        }
+
+        let search = test::init_service(App::new()
+            .app_data(app_data.clone())
+            .service(web::scope("/recipes_by_ingredients").configure(recipes_by_ingredients))).await;
+        let search_request = test::TestRequest::post()
+            .uri("/recipes_by_ingredients")
+            .set_json(serde_json::json!(["eggs", "flour"]))
+            .to_request();
+        let matches: serde_json::Value = test::call_and_read_body_json(&search, search_request).await;
+
+        // Nothing missing first, in alphabetical order, the milkshake uses none of them
+        assert_eq!(matches, serde_json::json!([
+            { "name": "Crepes", "missing": [] },
+            { "name": "Omelette", "missing": [] },
+            { "name": "Pancakes", "missing": ["milk"] },
+            { "name": "Cookies", "missing": ["milk", "sugar"] },
+        ]));
+
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,327 @@
+use actix_web::{HttpRequest, HttpResponse, web};
+use chrono::{DateTime, TimeZone, Utc};
+use diesel::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use super::{auth, db, models};
+use super::audit::{Action, Event};
+use super::auth::guard::{AdminRole, Auth};
+use super::auth::Role;
+use super::auth_endpoint::{invalid_credentials, password_reset_link, send_password_reset_mail};
+use super::db::prelude::*;
+
+
+pub fn users(cfg: &mut web::ServiceConfig) {
+    cfg
+        .service(get_users)
+        .service(post_user)
+        .service(put_role)
+        .service(post_password_reset)
+        .service(post_disable)
+        .service(post_enable)
+        .service(delete_tokens);
+}
+
+
+/// Logs the user out everywhere and revokes their personal access tokens and password reset links
+fn revoke_tokens(app_data: &models::AppData, conn: &mut db::Conn, username: &str) -> Result<(), ()> {
+    app_data.jwt_conf.invalidate_sessions(username, None).map_err(|_| ())?;
+    db::api_tokens::revoke_all(conn, username).map_err(|_| ())?;
+    db::password_resets::remove(conn, username).map_err(|_| ())?;
+    Ok(())
+}
+
+/// Checks if the user exists
+fn user_exists(conn: &mut db::Conn, username: &str) -> Result<bool, diesel::result::Error> {
+    diesel::select(diesel::dsl::exists(users_dsl::users.find(username)))
+        .get_result(conn)
+}
+
+
+#[derive(Deserialize)]
+struct PageParams {
+    /// Starts at 1
+    #[serde(default = "default_page")]
+    page: i64,
+    #[serde(default = "default_per_page")]
+    per_page: i64,
+}
+
+fn default_page() -> i64 { 1 }
+fn default_per_page() -> i64 { 50 }
+
+#[actix_web::get("")]
+async fn get_users(
+    _auth: Auth<AdminRole>,
+    app_data: web::Data<models::AppData>,
+    query_params: web::Query<PageParams>,
+) -> HttpResponse {
+    #[derive(Serialize)]
+    struct User {
+        username: String,
+        role: Role,
+        email: Option<String>,
+        /// None if the account is enabled
+        disabled_at: Option<DateTime<Utc>>,
+        disabled_reason: Option<String>,
+    }
+
+    #[derive(Serialize)]
+    struct ResponseData {
+        users: Vec<User>,
+        page: i64,
+        per_page: i64,
+        /// Number of all the users
+        total: i64,
+    }
+
+    if query_params.page < 1 || ! (1..=100).contains(&query_params.per_page) {
+        return HttpResponse::BadRequest().finish();
+    }
+
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    let offset = (query_params.page - 1) * query_params.per_page;
+    let (users, total) = match db::users::get_page(&mut conn, offset, query_params.per_page) {
+        Ok(val) => val,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    HttpResponse::Ok().json(ResponseData {
+        users: users.into_iter()
+            .map(|user| User {
+                role: user.role.parse().unwrap_or_default(),
+                username: user.username,
+                email: user.email,
+                disabled_at: user.disabled_at.map(|val| Utc.timestamp_opt(val, 0).unwrap()),
+                disabled_reason: user.disabled_reason,
+            })
+            .collect(),
+        page: query_params.page,
+        per_page: query_params.per_page,
+        total,
+    })
+}
+
+
+#[derive(Deserialize)]
+struct NewUserData {
+    username: String,
+    password: String,
+    #[serde(default)]
+    role: Role,
+    email: Option<String>,
+}
+
+/// Creates a new user, the same rules apply as on registration
+#[actix_web::post("")]
+async fn post_user(
+    req: HttpRequest,
+    auth: Auth<AdminRole>,
+    app_data: web::Data<models::AppData>,
+    user_data: web::Json<NewUserData>,
+) -> HttpResponse {
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    let result = db::users::create(
+        &mut conn,
+        &app_data.credential_policy,
+        &user_data.username,
+        &user_data.password,
+        user_data.role,
+        user_data.email.as_deref(),
+    );
+
+    match result {
+        Ok(_) => {
+            Event::new(&req, Some(&auth.claims.get_username()), Action::UserCreated).target(&user_data.username).record(&mut conn);
+            HttpResponse::Ok().finish()
+        },
+        Err(db::users::CreateError::Invalid(violations)) => invalid_credentials(violations),
+        Err(db::users::CreateError::Exists) => HttpResponse::Conflict().finish(),
+        Err(db::users::CreateError::Database(_)) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+
+#[derive(Deserialize)]
+struct RoleData {
+    role: Role,
+}
+
+/// The new role applies right away, the guards look up the current role on every request
+#[actix_web::put("/{username}/role")]
+async fn put_role(
+    path: web::Path<String>,
+    req: HttpRequest,
+    auth: Auth<AdminRole>,
+    app_data: web::Data<models::AppData>,
+    role_data: web::Json<RoleData>,
+) -> HttpResponse {
+    let username = path.into_inner();
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    // The server doesn't start without an admin
+    if role_data.role != Role::Admin {
+        match db::users::is_last_admin(&mut conn, &username) {
+            Ok(false) => {},
+            Ok(true) => return HttpResponse::Conflict().finish(),
+            Err(_) => return HttpResponse::InternalServerError().finish(),
+        }
+    }
+
+    match db::users::set_role(&mut conn, &username, role_data.role) {
+        Ok(0) => HttpResponse::NotFound().finish(),
+        Ok(_) => {
+            Event::new(&req, Some(&auth.claims.get_username()), Action::RoleChanged).target(&username).record(&mut conn);
+            HttpResponse::Ok().finish()
+        },
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+
+/// The current password stops working and the user gets logged out everywhere.
+/// The reset link gets mailed to the user when possible, it is returned either way so it can be handed over
+#[actix_web::post("/{username}/password_reset")]
+async fn post_password_reset(
+    path: web::Path<String>,
+    req: HttpRequest,
+    auth: Auth<AdminRole>,
+    app_data: web::Data<models::AppData>,
+) -> HttpResponse {
+    #[derive(Serialize)]
+    struct ResponseData {
+        reset_link: String,
+        /// false if the user has no email or sending mail is turned off
+        mailed: bool,
+    }
+
+    let username = path.into_inner();
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    let email: Option<String> = match users_dsl::users
+        .select(users_dsl::email)
+        .find(&username)
+        .first(&mut conn) {
+        Ok(val) => val,
+        Err(diesel::result::Error::NotFound) => return HttpResponse::NotFound().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    // Nobody knows the random password, so only the reset link gets the user back in
+    let random_password: String = {
+        let mut rng = rand::thread_rng();
+        (0..32)
+            .map(|_| rng.sample(rand::distributions::Alphanumeric) as char)
+            .collect()
+    };
+    let query_result = diesel::update(users_dsl::users.find(&username))
+        .set(users_dsl::password_hash.eq(auth::hash_password(&random_password)))
+        .execute(&mut conn);
+    if query_result.is_err() || revoke_tokens(&app_data, &mut conn, &username).is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let reset_link = match password_reset_link(&mut conn, &username) {
+        Ok(val) => val,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    Event::new(&req, Some(&auth.claims.get_username()), Action::PasswordResetIssued).target(&username).record(&mut conn);
+
+    let mailed = match (&app_data.mailer, email) {
+        (Some(mailer), Some(email)) => {
+            send_password_reset_mail(mailer.clone(), &username, email, &reset_link);
+            true
+        }
+        _ => false,
+    };
+
+    HttpResponse::Ok().json(ResponseData { reset_link, mailed })
+}
+
+
+#[derive(Deserialize)]
+struct DisableData {
+    /// Shown to the user when they try to log in
+    reason: Option<String>,
+}
+
+/// Disabled users can't log in and get logged out everywhere
+#[actix_web::post("/{username}/disable")]
+async fn post_disable(
+    path: web::Path<String>,
+    req: HttpRequest,
+    auth: Auth<AdminRole>,
+    app_data: web::Data<models::AppData>,
+    disable_data: Option<web::Json<DisableData>>,
+) -> HttpResponse {
+    let username = path.into_inner();
+    if username == auth.claims.get_username() {
+        return HttpResponse::Conflict().finish();
+    }
+
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    let reason = disable_data.as_ref().and_then(|val| val.reason.as_deref());
+    match db::users::disable(&mut conn, &username, reason) {
+        Ok(0) => return HttpResponse::NotFound().finish(),
+        Ok(_) => {},
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    }
+    Event::new(&req, Some(&auth.claims.get_username()), Action::UserDisabled).target(&username).record(&mut conn);
+
+    match revoke_tokens(&app_data, &mut conn, &username) {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+
+#[actix_web::post("/{username}/enable")]
+async fn post_enable(
+    path: web::Path<String>,
+    req: HttpRequest,
+    auth: Auth<AdminRole>,
+    app_data: web::Data<models::AppData>,
+) -> HttpResponse {
+    let username = path.into_inner();
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    match db::users::enable(&mut conn, &username) {
+        Ok(0) => HttpResponse::NotFound().finish(),
+        Ok(_) => {
+            Event::new(&req, Some(&auth.claims.get_username()), Action::UserEnabled).target(&username).record(&mut conn);
+            HttpResponse::Ok().finish()
+        },
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+
+/// Logs the user out everywhere and revokes their personal access tokens
+#[actix_web::delete("/{username}/tokens")]
+async fn delete_tokens(
+    path: web::Path<String>,
+    req: HttpRequest,
+    auth: Auth<AdminRole>,
+    app_data: web::Data<models::AppData>,
+) -> HttpResponse {
+    let username = path.into_inner();
+    let mut conn: db::Conn = super::get_conn!(app_data.pool);
+
+    match user_exists(&mut conn, &username) {
+        Ok(true) => {},
+        Ok(false) => return HttpResponse::NotFound().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    }
+
+    match revoke_tokens(&app_data, &mut conn, &username) {
+        Ok(_) => {
+            Event::new(&req, Some(&auth.claims.get_username()), Action::TokensRevoked).target(&username).record(&mut conn);
+            HttpResponse::Ok().finish()
+        },
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
@@ -1,3 +1,5 @@
 pub mod api_v1;
+pub mod well_known;
 
 pub use api_v1::api_v1;
+pub use well_known::well_known;
@@ -0,0 +1,18 @@
+use actix_web::{HttpResponse, web};
+
+use crate::models;
+
+
+pub fn well_known(cfg: &mut web::ServiceConfig) {
+    cfg
+        .service(get_jwks);
+}
+
+
+/// Public keys for verifying our tokens, empty when signing with a shared secret
+#[actix_web::get("/jwks.json")]
+async fn get_jwks(
+    app_data: web::Data<models::AppData>,
+) -> HttpResponse {
+    HttpResponse::Ok().json(app_data.jwt_conf.jwks())
+}
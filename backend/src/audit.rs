@@ -0,0 +1,224 @@
+//! ## Append-only log of security related events
+//!
+//! Written by the auth endpoints, the admin endpoints and the setup commands.
+//! Failing to write an event doesn't fail the action itself, the error only gets logged
+
+use crate::{auth::token_storage::ClientInfo, db, models};
+use actix_web::HttpRequest;
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+/// User agent of the events caused by the setup commands, they have no ip
+pub const CLI_USER_AGENT: &str = "cli";
+
+
+/// ## What happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    LogIn,
+    /// Wrong password or two-factor code, the target is the username
+    LogInFailed,
+    LogOut,
+    Register,
+    PasswordChanged,
+    PasswordResetRequested,
+    /// Done through the emailed link
+    PasswordReset,
+    AccountDeleted,
+    DataExported,
+    EmailChanged,
+    SessionRevoked,
+    SessionsRevoked,
+    TokenCreated,
+    TokenRevoked,
+    TwoFactorEnabled,
+    TwoFactorDisabled,
+    /// Done by an admin, the user lost both the authenticator and the recovery codes
+    TwoFactorReset,
+    UserCreated,
+    RoleChanged,
+    /// Done by an admin, the password of the user stops working
+    PasswordResetIssued,
+    UserDisabled,
+    UserEnabled,
+    /// Done by an admin, the user gets logged out everywhere
+    TokensRevoked,
+    UserUnlocked,
+    InvitationCreated,
+    InvitationRevoked,
+    JwtKeyCreated,
+    JwtKeyActivated,
+    JwtKeyRetired,
+    IngredientCreated,
+    /// The target is "{old name} -> {new name}"
+    IngredientRenamed,
+    IngredientDeleted,
+    /// The target is the name of the setting
+    SettingChanged,
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::LogIn => write!(f, "log_in"),
+            Action::LogInFailed => write!(f, "log_in_failed"),
+            Action::LogOut => write!(f, "log_out"),
+            Action::Register => write!(f, "register"),
+            Action::PasswordChanged => write!(f, "password_changed"),
+            Action::PasswordResetRequested => write!(f, "password_reset_requested"),
+            Action::PasswordReset => write!(f, "password_reset"),
+            Action::AccountDeleted => write!(f, "account_deleted"),
+            Action::DataExported => write!(f, "data_exported"),
+            Action::EmailChanged => write!(f, "email_changed"),
+            Action::SessionRevoked => write!(f, "session_revoked"),
+            Action::SessionsRevoked => write!(f, "sessions_revoked"),
+            Action::TokenCreated => write!(f, "token_created"),
+            Action::TokenRevoked => write!(f, "token_revoked"),
+            Action::TwoFactorEnabled => write!(f, "two_factor_enabled"),
+            Action::TwoFactorDisabled => write!(f, "two_factor_disabled"),
+            Action::TwoFactorReset => write!(f, "two_factor_reset"),
+            Action::UserCreated => write!(f, "user_created"),
+            Action::RoleChanged => write!(f, "role_changed"),
+            Action::PasswordResetIssued => write!(f, "password_reset_issued"),
+            Action::UserDisabled => write!(f, "user_disabled"),
+            Action::UserEnabled => write!(f, "user_enabled"),
+            Action::TokensRevoked => write!(f, "tokens_revoked"),
+            Action::UserUnlocked => write!(f, "user_unlocked"),
+            Action::InvitationCreated => write!(f, "invitation_created"),
+            Action::InvitationRevoked => write!(f, "invitation_revoked"),
+            Action::JwtKeyCreated => write!(f, "jwt_key_created"),
+            Action::JwtKeyActivated => write!(f, "jwt_key_activated"),
+            Action::JwtKeyRetired => write!(f, "jwt_key_retired"),
+            Action::IngredientCreated => write!(f, "ingredient_created"),
+            Action::IngredientRenamed => write!(f, "ingredient_renamed"),
+            Action::IngredientDeleted => write!(f, "ingredient_deleted"),
+            Action::SettingChanged => write!(f, "setting_changed"),
+        }
+    }
+}
+
+
+/// ## An event about to be recorded
+#[derive(Debug, Clone)]
+pub struct Event {
+    actor: Option<String>,
+    action: Action,
+    target: Option<String>,
+    client: ClientInfo,
+}
+
+impl Event {
+    /// Caused by a request, the actor is None for anonymous requests
+    pub fn new(req: &HttpRequest, actor: Option<&str>, action: Action) -> Self {
+        Event {
+            actor: actor.map(str::to_owned),
+            action,
+            target: None,
+            client: ClientInfo::from_request(req),
+        }
+    }
+
+    /// Caused by a setup command
+    pub fn cli(action: Action) -> Self {
+        Event {
+            actor: None,
+            action,
+            target: None,
+            client: ClientInfo {
+                user_agent: Some(CLI_USER_AGENT.to_owned()),
+                ip: None,
+            },
+        }
+    }
+
+    /// The user, token or setting the action was done to
+    pub fn target(mut self, target: impl ToString) -> Self {
+        self.target = Some(target.to_string());
+        self
+    }
+
+    pub fn record(self, conn: &mut db::Conn) {
+        let result = db::audit_events::record(conn, &models::AuditEventInsertable {
+            actor: self.actor,
+            action: self.action.to_string(),
+            target: self.target,
+            ip: self.client.ip,
+            user_agent: self.client.user_agent,
+            created_at: Utc::now().timestamp(),
+        });
+        if let Err(err) = result {
+            tracing::error!(action = %self.action, %err, "Couldn't record the audit event");
+        }
+    }
+}
+
+
+/// ## A recorded event as shown to the admins
+#[derive(Debug, Clone, Serialize)]
+pub struct Entry {
+    pub id: i32,
+    pub actor: Option<String>,
+    pub action: String,
+    pub target: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<models::AuditEvent> for Entry {
+    fn from(event: models::AuditEvent) -> Self {
+        Entry {
+            id: event.id,
+            actor: event.actor,
+            action: event.action,
+            target: event.target,
+            ip: event.ip,
+            user_agent: event.user_agent,
+            created_at: Utc.timestamp_opt(event.created_at, 0).unwrap(),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diesel::prelude::*;
+
+    #[test]
+    fn append_only_and_filtered() {
+        let (pool, path) = db::test_pool("audit");
+        let mut conn = pool.get().unwrap();
+
+        Event::cli(Action::UserCreated).target("alice").record(&mut conn);
+        Event::cli(Action::RoleChanged).target("alice").record(&mut conn);
+        Event::cli(Action::UserCreated).target("bob").record(&mut conn);
+
+        let filter = db::audit_events::Filter {
+            action: Some(Action::UserCreated.to_string()),
+            ..Default::default()
+        };
+        let (events, total) = db::audit_events::get_page(&mut conn, &filter, 0, 1).unwrap();
+        assert_eq!(total, 2);
+        assert_eq!(events[0].target.as_deref(), Some("bob"));
+        assert_eq!(events[0].user_agent.as_deref(), Some(CLI_USER_AGENT));
+
+        let filter = db::audit_events::Filter {
+            target: Some("alice".to_owned()),
+            ..Default::default()
+        };
+        let events = db::audit_events::get_all(&mut conn, &filter).unwrap();
+        let actions: Vec<&str> = events.iter().map(|val| val.action.as_str()).collect();
+        assert_eq!(actions, ["user_created", "role_changed"]);
+
+        // The recorded events can't be changed or removed
+        use crate::schema::audit_events::dsl;
+        assert!(diesel::update(dsl::audit_events).set(dsl::actor.eq("mallory")).execute(&mut conn).is_err());
+        assert!(diesel::delete(dsl::audit_events).execute(&mut conn).is_err());
+        assert_eq!(db::audit_events::get_all(&mut conn, &Default::default()).unwrap().len(), 3);
+
+        drop(conn);
+        let _ = std::fs::remove_file(&path);
+    }
+}
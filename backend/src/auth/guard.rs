@@ -0,0 +1,181 @@
+//! ## Extractor used to authenticate requests
+//!
+//! ### Example use
+//! ```
+//! #[actix_web::delete("/{name}")]
+//! async fn handler(auth: Auth<AdminRole>) -> HttpResponse {
+//!     let username = auth.claims.get_username();
+//!     ...
+//! }
+//! ```
+//! The access token is read from the `Authorization: Bearer` header or the `access_token` cookie.
+//! Personal access tokens are only accepted when the handler asks for a scope, like `Auth<UserRole, RecipesWriteScope>`.
+//! Responds with 401 if the access token is missing or invalid and with 403 if the role or scope is insufficient.
+//! `Option<Auth>` can be used when being logged in isn't required
+
+use crate::db;
+use crate::models::AppData;
+use super::jwt::{JwtDeserialized, JwtType};
+use super::{CookieName, Role, Scope};
+use actix_web::{dev::Payload, error::InternalError, web, FromRequest, HttpRequest, HttpResponse};
+use chrono::{TimeZone, Utc};
+use std::{future::{ready, Ready}, marker::PhantomData};
+
+
+/// ## The minimal role needed to pass the guard
+pub trait RequiredRole {
+    const ROLE: Role;
+}
+
+pub struct UserRole;
+#[allow(dead_code)]
+pub struct EditorRole;
+pub struct AdminRole;
+
+impl RequiredRole for UserRole {
+    const ROLE: Role = Role::User;
+}
+
+impl RequiredRole for EditorRole {
+    const ROLE: Role = Role::Editor;
+}
+
+impl RequiredRole for AdminRole {
+    const ROLE: Role = Role::Admin;
+}
+
+
+/// ## The scope a personal access token needs to pass the guard
+/// Logged in sessions aren't limited by scopes
+pub trait RequiredScope {
+    /// None means that personal access tokens aren't accepted at all
+    const SCOPE: Option<Scope>;
+}
+
+pub struct SessionOnly;
+pub struct RecipesWriteScope;
+pub struct IngredientsWriteScope;
+pub struct ProfileReadScope;
+
+impl RequiredScope for SessionOnly {
+    const SCOPE: Option<Scope> = None;
+}
+
+impl RequiredScope for RecipesWriteScope {
+    const SCOPE: Option<Scope> = Some(Scope::RecipesWrite);
+}
+
+impl RequiredScope for IngredientsWriteScope {
+    const SCOPE: Option<Scope> = Some(Scope::IngredientsWrite);
+}
+
+impl RequiredScope for ProfileReadScope {
+    const SCOPE: Option<Scope> = Some(Scope::ProfileRead);
+}
+
+
+/// ## Claims of a request with a valid access token
+pub struct Auth<R: RequiredRole = UserRole, S: RequiredScope = SessionOnly> {
+    pub claims: JwtDeserialized,
+    /// Scopes of the personal access token, None for logged in sessions
+    pub scopes: Option<Vec<Scope>>,
+    _marker: PhantomData<(R, S)>,
+}
+
+impl<R: RequiredRole, S: RequiredScope> Auth<R, S> {
+    /// Sessions can do everything, personal access tokens only what their scopes allow
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        match &self.scopes {
+            Some(scopes) => scopes.contains(&scope),
+            None => true,
+        }
+    }
+
+    fn from_request_sync(req: &HttpRequest) -> Result<Self, actix_web::Error> {
+        let unauthorized = || InternalError::from_response("", HttpResponse::Unauthorized().finish());
+        let forbidden = || InternalError::from_response("", HttpResponse::Forbidden().finish());
+
+        let app_data = match req.app_data::<web::Data<AppData>>() {
+            Some(val) => val,
+            None => return Err(InternalError::from_response("", HttpResponse::InternalServerError().finish()).into()),
+        };
+        let jwt_conf = &app_data.jwt_conf;
+
+        // Try to get the access token
+        let access_token = match super::request_token(req, CookieName::AccessToken) {
+            Some(val) => val,
+            None => return Err(unauthorized().into()),
+        };
+
+        let (claims, scopes) = if access_token.starts_with(db::api_tokens::TOKEN_PREFIX) {
+            // Personal access token, only the scopes that the handler asked for are allowed
+            let required_scope = match S::SCOPE {
+                Some(val) => val,
+                None => return Err(forbidden().into()),
+            };
+
+            let mut conn = match app_data.pool.get() {
+                Ok(val) => val,
+                Err(_) => return Err(InternalError::from_response("", HttpResponse::InternalServerError().finish()).into()),
+            };
+            let (api_token, role) = match db::api_tokens::authenticate(&mut conn, &access_token) {
+                Ok(Some(val)) => val,
+                Ok(None) => return Err(unauthorized().into()),
+                Err(_) => return Err(InternalError::from_response("", HttpResponse::InternalServerError().finish()).into()),
+            };
+
+            let scopes: Vec<Scope> = api_token.scopes
+                .split_whitespace()
+                .filter_map(|scope| scope.parse().ok())
+                .collect();
+            if ! scopes.contains(&required_scope) {
+                return Err(forbidden().into());
+            }
+
+            let claims = JwtDeserialized::from_api_token(
+                &api_token.username,
+                role.parse().unwrap_or_default(),
+                Utc.timestamp_opt(api_token.created_at, 0).unwrap(),
+                api_token.expiration.map(|val| Utc.timestamp_opt(val, 0).unwrap()),
+            );
+            (claims, Some(scopes))
+        } else {
+            // Validate and deserialize the jwt
+            let jwt = jwt_conf.jwt_from_str(access_token);
+            let claims = match jwt_conf.validate(jwt) {
+                Some(val) if val.get_jwt_type() == JwtType::AccessToken => val,
+                _ => return Err(unauthorized().into()),
+            };
+
+            // The token outlives role changes and the account being disabled, so they have to be checked every time
+            let mut conn = match app_data.pool.get() {
+                Ok(val) => val,
+                Err(_) => return Err(InternalError::from_response("", HttpResponse::InternalServerError().finish()).into()),
+            };
+            match db::users::current_role(&mut conn, &claims.get_username()) {
+                Ok(Some(role)) => (claims.with_role(role), None),
+                Ok(None) => return Err(unauthorized().into()),
+                Err(_) => return Err(InternalError::from_response("", HttpResponse::InternalServerError().finish()).into()),
+            }
+        };
+
+        if claims.get_role() < R::ROLE {
+            return Err(forbidden().into());
+        }
+
+        Ok(Auth {
+            claims,
+            scopes,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<R: RequiredRole, S: RequiredScope> FromRequest for Auth<R, S> {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Self::from_request_sync(req))
+    }
+}
@@ -0,0 +1,76 @@
+//! ## Slows down password guessing for a single username
+//!
+//! The first few failures are free, then every failure doubles the wait before the next try,
+//! until the account gets locked out for a while
+
+use crate::models::LoginAttempt;
+
+
+/// Failures allowed before the backoff starts
+const FREE_ATTEMPTS: i32 = 3;
+/// Failures after which the account gets locked out
+const LOCKOUT_ATTEMPTS: i32 = 10;
+const LOCKOUT_SECONDS: i64 = 15 * 60;
+/// Failures older than this are forgotten
+pub const RESET_SECONDS: i64 = 60 * 60;
+
+
+/// ## When the next log in attempt is allowed
+/// ### Returns
+/// A unix timestamp, if it's in the past, the attempt is allowed
+pub fn blocked_until(attempt: &LoginAttempt) -> i64 {
+    if attempt.failed_count < FREE_ATTEMPTS {
+        return attempt.last_failed;
+    }
+    if LOCKOUT_ATTEMPTS <= attempt.failed_count {
+        return attempt.last_failed + LOCKOUT_SECONDS;
+    }
+    attempt.last_failed + (1 << (attempt.failed_count - FREE_ATTEMPTS))
+}
+
+/// ## Seconds left until the next log in attempt is allowed
+/// ### Returns
+/// None if the attempt is allowed right now
+pub fn retry_after(attempt: Option<&LoginAttempt>, unix_time: i64) -> Option<i64> {
+    let attempt = attempt?;
+    if RESET_SECONDS <= unix_time - attempt.last_failed {
+        return None;
+    }
+
+    let blocked_until = blocked_until(attempt);
+    if blocked_until <= unix_time {
+        return None;
+    }
+    Some(blocked_until - unix_time)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attempt(failed_count: i32, last_failed: i64) -> LoginAttempt {
+        LoginAttempt { username: "user".to_owned(), failed_count, last_failed }
+    }
+
+    #[test]
+    fn backoff() {
+        let now = 1_700_000_000;
+
+        assert_eq!(retry_after(None, now), None);
+        assert_eq!(retry_after(Some(&attempt(FREE_ATTEMPTS - 1, now)), now), None);
+
+        // Doubles with every failure
+        assert_eq!(retry_after(Some(&attempt(FREE_ATTEMPTS, now)), now), Some(1));
+        assert_eq!(retry_after(Some(&attempt(FREE_ATTEMPTS + 3, now)), now), Some(8));
+        assert_eq!(retry_after(Some(&attempt(FREE_ATTEMPTS + 3, now - 8)), now), None);
+
+        // Locked out
+        assert_eq!(retry_after(Some(&attempt(LOCKOUT_ATTEMPTS + 5, now)), now), Some(LOCKOUT_SECONDS));
+        assert_eq!(retry_after(Some(&attempt(LOCKOUT_ATTEMPTS, now - LOCKOUT_SECONDS)), now), None);
+
+        // Forgotten
+        assert_eq!(retry_after(Some(&attempt(LOCKOUT_ATTEMPTS, now - RESET_SECONDS)), now), None);
+    }
+}
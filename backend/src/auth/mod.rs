@@ -1,22 +1,98 @@
+pub mod guard;
 pub mod jwt;
+pub mod lockout;
+pub mod token_storage;
+pub mod totp;
 
+use crate::db;
 use argon2::password_hash::{ rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString };
 use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+
+/// Set once on startup from the key_value table, the argon2 defaults are used until then
+static ARGON2_PARAMS: OnceLock<argon2::Params> = OnceLock::new();
 
 lazy_static! {
-    static ref ARGON2_CONF: argon2::Argon2<'static> = {
-        argon2::Argon2::default()
+    /// Checked when the user doesn't exist, so the response takes just as long as with a wrong password
+    static ref DUMMY_HASH: String = hash_password("dummy password");
+}
+
+fn argon2_conf() -> argon2::Argon2<'static> {
+    argon2::Argon2::new(
+        argon2::Algorithm::Argon2id,
+        argon2::Version::V0x13,
+        ARGON2_PARAMS.get().cloned().unwrap_or_default(),
+    )
+}
+
+/// ## Validates the argon2 parameters
+/// Memory is in KiB
+pub fn argon2_params(memory: u32, iterations: u32, parallelism: u32) -> Result<argon2::Params, String> {
+    argon2::Params::new(memory, iterations, parallelism, None)
+        .map_err(|err| format!("Invalid password hashing parameters: {}", err))
+}
+
+/// ## Loads the argon2 parameters from the key_value table
+/// The missing ones fall back to the argon2 defaults
+pub fn load_argon2_params(conn: &mut db::Conn) -> Result<argon2::Params, String> {
+    let mut get = |key: &str, default: u32| match db::key_value::get(conn, key) {
+        Ok(val) => val.parse::<u32>().map_err(|_| format!("Invalid value of \"{}\"", key)),
+        Err(diesel::result::Error::NotFound) => Ok(default),
+        Err(err) => Err(format!("Couldn't load \"{}\": {}", key, err)),
     };
+
+    let memory = get("argon2_memory", argon2::Params::DEFAULT_M_COST)?;
+    let iterations = get("argon2_iterations", argon2::Params::DEFAULT_T_COST)?;
+    let parallelism = get("argon2_parallelism", argon2::Params::DEFAULT_P_COST)?;
+    argon2_params(memory, iterations, parallelism)
+}
+
+/// ## Sets the parameters used for hashing new passwords
+/// Only the first call has an effect, so it has to happen before any password gets hashed
+pub fn set_argon2_params(params: argon2::Params) {
+    let _ = ARGON2_PARAMS.set(params);
 }
 
 pub fn hash_password(password: &str) -> String {
     let salt = SaltString::generate(&mut OsRng);
-    ARGON2_CONF.hash_password(password.as_bytes(), &salt).unwrap().to_string()
+    argon2_conf().hash_password(password.as_bytes(), &salt).unwrap().to_string()
 }
 
+/// ## Checks the password against a stored hash
+/// A malformed hash never matches
 pub fn verify_password(password: &str, hashed_password: &str) -> bool {
-    let parsed_hash = PasswordHash::new(hashed_password).unwrap();
-    ARGON2_CONF.verify_password(password.as_bytes(), &parsed_hash).is_ok()
+    // The parameters are taken from the hash itself
+    match PasswordHash::new(hashed_password) {
+        Ok(parsed_hash) => argon2_conf().verify_password(password.as_bytes(), &parsed_hash).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// ## Checks if the hash was made with other than the current parameters
+/// Such hashes should be replaced when the password is at hand, i.e. on log in
+pub fn needs_rehash(hashed_password: &str) -> bool {
+    let parsed_hash = match PasswordHash::new(hashed_password) {
+        Ok(val) => val,
+        Err(_) => return true,
+    };
+    if parsed_hash.algorithm != argon2::Algorithm::Argon2id.ident()
+        || parsed_hash.version != Some(argon2::Version::V0x13.into()) {
+        return true;
+    }
+
+    let current = ARGON2_PARAMS.get().cloned().unwrap_or_default();
+    match argon2::Params::try_from(&parsed_hash) {
+        Ok(val) => val.m_cost() != current.m_cost()
+            || val.t_cost() != current.t_cost()
+            || val.p_cost() != current.p_cost(),
+        Err(_) => true,
+    }
+}
+
+/// ## Does the same work as `verify_password` for a user that doesn't exist
+pub fn verify_dummy_password(password: &str) {
+    verify_password(password, &DUMMY_HASH);
 }
 
 
@@ -34,3 +110,149 @@ impl std::fmt::Display for CookieName {
         }
     }
 }
+
+
+/// ## Gets the token sent with the request
+/// The `Authorization: Bearer` header is used by clients without cookies and takes precedence over the cookie
+pub fn request_token(req: &actix_web::HttpRequest, cookie_name: CookieName) -> Option<String> {
+    let bearer = req.headers()
+        .get(actix_web::http::header::AUTHORIZATION)
+        .and_then(|val| val.to_str().ok())
+        .and_then(|val| val.strip_prefix("Bearer "))
+        .map(|val| val.trim().to_owned());
+
+    match bearer {
+        Some(val) => Some(val),
+        None => req.cookie(&cookie_name.to_string()).map(|val| val.value().to_owned()),
+    }
+}
+
+
+/// ## Role of a user, ordered from the least to the most privileged
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+pub enum Role {
+    #[default]
+    #[serde(rename = "user")] User,
+    /// Can update and delete recipes of other users
+    #[serde(rename = "editor")] Editor,
+    #[serde(rename = "admin")] Admin,
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Role::User => write!(f, "user"),
+            Role::Editor => write!(f, "editor"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "user" => Ok(Role::User),
+            "editor" => Ok(Role::Editor),
+            "admin" => Ok(Role::Admin),
+            _ => Err(()),
+        }
+    }
+}
+
+
+/// ## What a personal access token is allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Scope {
+    #[serde(rename = "recipes:write")] RecipesWrite,
+    /// Only works for admins
+    #[serde(rename = "ingredients:write")] IngredientsWrite,
+    #[serde(rename = "profile:read")] ProfileRead,
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Scope::RecipesWrite => write!(f, "recipes:write"),
+            Scope::IngredientsWrite => write!(f, "ingredients:write"),
+            Scope::ProfileRead => write!(f, "profile:read"),
+        }
+    }
+}
+
+impl std::str::FromStr for Scope {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "recipes:write" => Ok(Scope::RecipesWrite),
+            "ingredients:write" => Ok(Scope::IngredientsWrite),
+            "profile:read" => Ok(Scope::ProfileRead),
+            _ => Err(()),
+        }
+    }
+}
+
+
+/// ## Who is allowed to create an account through the api
+/// Stored under the "registration" key in the key_value table
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum RegistrationPolicy {
+    Open,
+    InviteOnly,
+    #[default]
+    Closed,
+}
+
+impl std::fmt::Display for RegistrationPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegistrationPolicy::Open => write!(f, "open"),
+            RegistrationPolicy::InviteOnly => write!(f, "invite_only"),
+            RegistrationPolicy::Closed => write!(f, "closed"),
+        }
+    }
+}
+
+impl std::str::FromStr for RegistrationPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(RegistrationPolicy::Open),
+            "invite_only" => Ok(RegistrationPolicy::InviteOnly),
+            "closed" => Ok(RegistrationPolicy::Closed),
+            _ => Err(()),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_hash() {
+        assert!(! verify_password("password", "not a hash"));
+        assert!(needs_rehash("not a hash"));
+    }
+
+    #[test]
+    fn rehash_on_other_params() {
+        let hash = hash_password("password");
+        assert!(verify_password("password", &hash));
+        assert!(! verify_password("wrong password", &hash));
+        assert!(! needs_rehash(&hash));
+
+        let salt = SaltString::generate(&mut OsRng);
+        let old_params = argon2_params(8 * 1024, 1, 1).unwrap();
+        let old_hash = argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, old_params)
+            .hash_password(b"password", &salt)
+            .unwrap()
+            .to_string();
+        assert!(verify_password("password", &old_hash));
+        assert!(needs_rehash(&old_hash));
+    }
+}
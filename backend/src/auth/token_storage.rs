@@ -0,0 +1,373 @@
+//! ## Backends used by the `TokenStore` to keep track of valid tokens
+//!
+//! Tokens are never stored directly, only their sha256 hashes
+
+use crate::db::{self, prelude::*};
+use crate::models;
+use super::jwt::JwtType;
+use chrono::{DateTime, TimeZone, Utc};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, sync::RwLock};
+
+
+/// ## Hashes a token for storage
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+
+#[derive(Debug)]
+pub struct StorageError(String);
+
+impl std::fmt::Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Token storage error: {}", self.0)
+    }
+}
+
+impl From<diesel::result::Error> for StorageError {
+    fn from(value: diesel::result::Error) -> Self {
+        StorageError(value.to_string())
+    }
+}
+
+impl From<diesel::r2d2::PoolError> for StorageError {
+    fn from(value: diesel::r2d2::PoolError) -> Self {
+        StorageError(value.to_string())
+    }
+}
+
+
+/// ## Where the user logged in from
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+impl ClientInfo {
+    pub fn from_request(req: &actix_web::HttpRequest) -> Self {
+        ClientInfo {
+            user_agent: req.headers()
+                .get(actix_web::http::header::USER_AGENT)
+                .and_then(|val| val.to_str().ok())
+                .map(str::to_owned),
+            ip: req.connection_info().realip_remote_addr().map(str::to_owned),
+        }
+    }
+}
+
+
+/// ## Data kept about every valid token
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenEntry {
+    pub jwt_type: JwtType,
+    pub username: String,
+    /// For rotated refresh tokens it stays the time of the log in
+    pub issuing: DateTime<Utc>,
+    pub expiration: DateTime<Utc>,
+    /// All tokens descending from one log in share a family
+    pub family: Option<String>,
+    /// Retired refresh tokens were already exchanged for new ones, presenting them again means that they were stolen
+    pub retired: bool,
+    pub client: ClientInfo,
+}
+
+
+/// ## Interface of a token storage backend
+pub trait TokenStorage: std::fmt::Debug + Send + Sync {
+    fn insert(&self, token_hash: &str, entry: TokenEntry) -> Result<(), StorageError>;
+    fn get(&self, token_hash: &str) -> Result<Option<TokenEntry>, StorageError>;
+    fn remove(&self, token_hash: &str) -> Result<(), StorageError>;
+    /// Marks the token as retired, it is kept until it expires so its reuse can be detected.
+    /// Checking and retiring is a single step, so only one of concurrent calls succeeds
+    /// ### Returns
+    /// false if the token was already retired or doesn't exist
+    fn retire(&self, token_hash: &str) -> Result<bool, StorageError>;
+    /// Removes all the tokens of a family
+    fn remove_family(&self, family: &str) -> Result<(), StorageError>;
+    /// Removes the access tokens of a family, the refresh tokens stay
+    fn remove_access_tokens(&self, family: &str) -> Result<(), StorageError>;
+    /// Gets the entries of all the tokens belonging to a user
+    fn get_by_user(&self, username: &str) -> Result<Vec<TokenEntry>, StorageError>;
+    /// Removes all the tokens that expired before `now`
+    fn clean(&self, now: DateTime<Utc>) -> Result<(), StorageError>;
+}
+
+
+/// ## Keeps the tokens in memory, they are lost on restart
+#[derive(Default)]
+pub struct MemoryStorage {
+    tokens: RwLock<HashMap<String, TokenEntry>>,
+}
+
+impl std::fmt::Debug for MemoryStorage {
+    // Leaves out the tokens
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemoryStorage")
+            .field("tokens", &self.tokens.read().unwrap().len())
+            .finish()
+    }
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl TokenStorage for MemoryStorage {
+    fn insert(&self, token_hash: &str, entry: TokenEntry) -> Result<(), StorageError> {
+        self.tokens.write().unwrap().insert(token_hash.to_owned(), entry);
+        Ok(())
+    }
+
+    fn get(&self, token_hash: &str) -> Result<Option<TokenEntry>, StorageError> {
+        Ok(self.tokens.read().unwrap().get(token_hash).cloned())
+    }
+
+    fn remove(&self, token_hash: &str) -> Result<(), StorageError> {
+        self.tokens.write().unwrap().remove(token_hash);
+        Ok(())
+    }
+
+    fn retire(&self, token_hash: &str) -> Result<bool, StorageError> {
+        match self.tokens.write().unwrap().get_mut(token_hash) {
+            Some(entry) if ! entry.retired => {
+                entry.retired = true;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn remove_family(&self, family: &str) -> Result<(), StorageError> {
+        self.tokens.write().unwrap().retain(|_, entry| {
+            entry.family.as_deref() != Some(family)
+        });
+        Ok(())
+    }
+
+    fn remove_access_tokens(&self, family: &str) -> Result<(), StorageError> {
+        self.tokens.write().unwrap().retain(|_, entry| {
+            entry.jwt_type != JwtType::AccessToken || entry.family.as_deref() != Some(family)
+        });
+        Ok(())
+    }
+
+    fn get_by_user(&self, username: &str) -> Result<Vec<TokenEntry>, StorageError> {
+        Ok(self.tokens.read().unwrap()
+            .values()
+            .filter(|entry| entry.username == username)
+            .cloned()
+            .collect())
+    }
+
+    /// Since designed to hold the write lock for as little time as possible, this isn't very memmory efficient
+    fn clean(&self, now: DateTime<Utc>) -> Result<(), StorageError> {
+        // Clone tokens
+        let mut tokens_clone = self.tokens.read().unwrap().clone();
+        // Clean cloned tokens
+        tokens_clone.retain(|_, entry| {
+            now < entry.expiration
+        });
+        // Overwrite tokens
+        *self.tokens.write().unwrap() = tokens_clone;
+        Ok(())
+    }
+}
+
+
+/// ## Keeps the tokens in the `tokens` table, so they survive restarts and can be shared between instances
+pub struct DatabaseStorage {
+    pool: db::Pool,
+}
+
+impl std::fmt::Debug for DatabaseStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "DatabaseStorage {{ ... }}")
+    }
+}
+
+impl DatabaseStorage {
+    pub fn new(pool: db::Pool) -> Self {
+        DatabaseStorage { pool }
+    }
+
+    fn to_entry(token: models::Token) -> TokenEntry {
+        TokenEntry {
+            jwt_type: match token.jwt_type.as_str() {
+                "refresh_token" => JwtType::RefreshToken,
+                "mfa_pending" => JwtType::MfaPending,
+                _ => JwtType::AccessToken,
+            },
+            username: token.username,
+            issuing: Utc.timestamp_opt(token.issuing, 0).unwrap(),
+            expiration: Utc.timestamp_opt(token.expiration, 0).unwrap(),
+            family: token.family,
+            retired: token.retired,
+            client: ClientInfo {
+                user_agent: token.user_agent,
+                ip: token.ip,
+            },
+        }
+    }
+}
+
+impl TokenStorage for DatabaseStorage {
+    fn insert(&self, token_hash: &str, entry: TokenEntry) -> Result<(), StorageError> {
+        let mut conn = self.pool.get()?;
+        diesel::replace_into(tokens_dsl::tokens)
+            .values(&models::Token {
+                token_hash: token_hash.to_owned(),
+                username: entry.username,
+                expiration: entry.expiration.timestamp(),
+                family: entry.family,
+                retired: entry.retired,
+                jwt_type: match entry.jwt_type {
+                    JwtType::AccessToken => "access_token".to_owned(),
+                    JwtType::RefreshToken => "refresh_token".to_owned(),
+                    JwtType::MfaPending => "mfa_pending".to_owned(),
+                },
+                issuing: entry.issuing.timestamp(),
+                user_agent: entry.client.user_agent,
+                ip: entry.client.ip,
+            })
+            .execute(&mut conn)?;
+        Ok(())
+    }
+
+    fn get(&self, token_hash: &str) -> Result<Option<TokenEntry>, StorageError> {
+        let mut conn = self.pool.get()?;
+        let result: Option<models::Token> = tokens_dsl::tokens
+            .find(token_hash)
+            .first(&mut conn)
+            .optional()?;
+
+        Ok(result.map(Self::to_entry))
+    }
+
+    fn remove(&self, token_hash: &str) -> Result<(), StorageError> {
+        let mut conn = self.pool.get()?;
+        diesel::delete(tokens_dsl::tokens.find(token_hash))
+            .execute(&mut conn)?;
+        Ok(())
+    }
+
+    fn retire(&self, token_hash: &str) -> Result<bool, StorageError> {
+        let mut conn = self.pool.get()?;
+        let retired = diesel::update(tokens_dsl::tokens.find(token_hash).filter(tokens_dsl::retired.eq(false)))
+            .set(tokens_dsl::retired.eq(true))
+            .execute(&mut conn)?;
+        Ok(retired == 1)
+    }
+
+    fn remove_family(&self, family: &str) -> Result<(), StorageError> {
+        let mut conn = self.pool.get()?;
+        diesel::delete(tokens_dsl::tokens.filter(tokens_dsl::family.eq(family)))
+            .execute(&mut conn)?;
+        Ok(())
+    }
+
+    fn remove_access_tokens(&self, family: &str) -> Result<(), StorageError> {
+        let mut conn = self.pool.get()?;
+        diesel::delete(tokens_dsl::tokens
+            .filter(tokens_dsl::family.eq(family))
+            .filter(tokens_dsl::jwt_type.eq("access_token")))
+            .execute(&mut conn)?;
+        Ok(())
+    }
+
+    fn get_by_user(&self, username: &str) -> Result<Vec<TokenEntry>, StorageError> {
+        let mut conn = self.pool.get()?;
+        let result: Vec<models::Token> = tokens_dsl::tokens
+            .filter(tokens_dsl::username.eq(username))
+            .load(&mut conn)?;
+
+        Ok(result.into_iter().map(Self::to_entry).collect())
+    }
+
+    fn clean(&self, now: DateTime<Utc>) -> Result<(), StorageError> {
+        let mut conn = self.pool.get()?;
+        diesel::delete(tokens_dsl::tokens.filter(tokens_dsl::expiration.le(now.timestamp())))
+            .execute(&mut conn)?;
+        Ok(())
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    /// Runs the same checks against any storage
+    fn check_storage(storage: &dyn TokenStorage) {
+        let now = Utc.timestamp_opt(Utc::now().timestamp(), 0).unwrap();
+        let entry = |expiration, family: &str| TokenEntry {
+            jwt_type: JwtType::RefreshToken,
+            username: "admin".to_owned(),
+            issuing: now,
+            expiration,
+            family: Some(family.to_owned()),
+            retired: false,
+            client: ClientInfo {
+                user_agent: Some("test".to_owned()),
+                ip: None,
+            },
+        };
+        let valid = entry(now + Duration::hours(1), "a");
+        let expired = entry(now - Duration::hours(1), "a");
+
+        storage.insert(&hash_token("valid"), valid.clone()).unwrap();
+        storage.insert(&hash_token("expired"), expired).unwrap();
+        assert_eq!(storage.get(&hash_token("valid")).unwrap(), Some(valid.clone()));
+        assert_eq!(storage.get(&hash_token("unknown")).unwrap(), None);
+
+        storage.clean(now).unwrap();
+        assert_eq!(storage.get(&hash_token("expired")).unwrap(), None);
+        assert_eq!(storage.get(&hash_token("valid")).unwrap(), Some(valid));
+
+        assert!(storage.retire(&hash_token("valid")).unwrap());
+        assert!(storage.get(&hash_token("valid")).unwrap().unwrap().retired);
+        // Only the first one retires it
+        assert!(! storage.retire(&hash_token("valid")).unwrap());
+        assert!(! storage.retire(&hash_token("unknown")).unwrap());
+
+        storage.remove(&hash_token("valid")).unwrap();
+        assert_eq!(storage.get(&hash_token("valid")).unwrap(), None);
+
+        storage.insert(&hash_token("first"), entry(now + Duration::hours(1), "a")).unwrap();
+        storage.insert(&hash_token("second"), entry(now + Duration::hours(1), "b")).unwrap();
+        assert_eq!(storage.get_by_user("admin").unwrap().len(), 2);
+        let access = TokenEntry { jwt_type: JwtType::AccessToken, ..entry(now + Duration::hours(1), "a") };
+        storage.insert(&hash_token("access"), access).unwrap();
+        storage.remove_access_tokens("a").unwrap();
+        assert_eq!(storage.get(&hash_token("access")).unwrap(), None);
+        assert!(storage.get(&hash_token("first")).unwrap().is_some());
+
+        storage.remove_family("a").unwrap();
+        assert_eq!(storage.get(&hash_token("first")).unwrap(), None);
+        assert!(storage.get(&hash_token("second")).unwrap().is_some());
+        assert_eq!(storage.get_by_user("admin").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn memory_storage() {
+        check_storage(&MemoryStorage::new());
+    }
+
+    #[test]
+    fn database_storage() {
+        let (pool, path) = db::test_pool("database_storage");
+        let mut conn = pool.get().unwrap();
+        diesel::sql_query("INSERT INTO users (username, password_hash) VALUES ('admin', '')")
+            .execute(&mut conn)
+            .unwrap();
+        drop(conn);
+
+        check_storage(&DatabaseStorage::new(pool));
+        let _ = std::fs::remove_file(path);
+    }
+}
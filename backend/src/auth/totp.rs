@@ -0,0 +1,132 @@
+//! ## Time based one time passwords as described in RFC 6238
+//!
+//! Uses the defaults that every authenticator app understands: HMAC-SHA1, 6 digits and 30 second steps
+
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha1::Sha1;
+
+
+const STEP_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+/// How many steps before and after the current one are accepted, to allow for clock drift
+const ALLOWED_DRIFT: i64 = 1;
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+
+/// ## Generates a new random secret, base32 encoded
+pub fn generate_secret() -> String {
+    let secret: [u8; 20] = rand::thread_rng().gen();
+    base32_encode(&secret)
+}
+
+/// ## Link that can be turned into a QR code for authenticator apps
+pub fn otpauth_url(secret: &str, username: &str) -> String {
+    let label: String = username.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect();
+    format!(
+        "otpauth://totp/CookBook:{}?secret={}&issuer=CookBook&algorithm=SHA1&digits={}&period={}",
+        label, secret, DIGITS, STEP_SECONDS,
+    )
+}
+
+/// ## Checks the code against the steps around `unix_time`
+/// Steps up to `last_step` were already used and are rejected
+/// ### Returns
+/// The step of the matching code, it should be stored as the new `last_step`
+pub fn verify(secret: &str, code: &str, unix_time: i64, last_step: i64) -> Option<i64> {
+    let secret = base32_decode(secret)?;
+    let code = code.trim();
+    if code.len() != DIGITS as usize || ! code.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+
+    let current_step = unix_time / STEP_SECONDS;
+    (current_step - ALLOWED_DRIFT..=current_step + ALLOWED_DRIFT)
+        .filter(|step| last_step < *step)
+        .find(|step| generate_code(&secret, *step as u64) == code)
+}
+
+fn generate_code(secret: &[u8], step: u64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // Dynamic truncation
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+    format!("{:0width$}", binary % 10u32.pow(DIGITS), width = DIGITS as usize)
+}
+
+
+fn base32_encode(data: &[u8]) -> String {
+    let mut output = String::with_capacity((data.len() * 8).div_ceil(5));
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for byte in data {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while 5 <= bits {
+            bits -= 5;
+            output.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if 0 < bits {
+        output.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    output
+}
+
+fn base32_decode(data: &str) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(data.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for char in data.trim_end_matches('=').bytes() {
+        let value = BASE32_ALPHABET.iter().position(|val| *val == char.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if 8 <= bits {
+            bits -= 8;
+            output.push((buffer >> bits) as u8);
+        }
+    }
+    Some(output)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test vectors from RFC 6238, cut down to 6 digits
+    #[test]
+    fn rfc_6238_vectors() {
+        let secret = base32_encode(b"12345678901234567890");
+        assert_eq!(base32_decode(&secret).unwrap(), b"12345678901234567890");
+
+        for (unix_time, code) in [(59, "287082"), (1111111109, "081804"), (1234567890, "005924"), (2000000000, "279037")] {
+            assert_eq!(generate_code(b"12345678901234567890", (unix_time / STEP_SECONDS) as u64), code);
+            assert_eq!(verify(&secret, code, unix_time, 0), Some(unix_time / STEP_SECONDS));
+        }
+    }
+
+    #[test]
+    fn rejects_reused_and_invalid_codes() {
+        let secret = generate_secret();
+        let unix_time = 1_700_000_000;
+        let code = generate_code(&base32_decode(&secret).unwrap(), (unix_time / STEP_SECONDS) as u64);
+
+        let step = verify(&secret, &code, unix_time, 0).unwrap();
+        assert_eq!(verify(&secret, &code, unix_time, step), None);
+        assert_eq!(verify(&secret, &code, unix_time + 10 * STEP_SECONDS, 0), None);
+        assert_eq!(verify(&secret, "12345", unix_time, 0), None);
+        assert_eq!(verify(&secret, "abcdef", unix_time, 0), None);
+    }
+}
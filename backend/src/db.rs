@@ -1,8 +1,17 @@
 // use diesel::prelude::*;
 use crate::exit_with_error;
 use diesel::{r2d2::{self, ConnectionManager}, SqliteConnection};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 use prelude::*;
 
+/// ## All the migrations, compiled into the binary
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+/// ## Version of the first migration
+/// Databases created before migrations were tracked were made with just this one
+const INIT_MIGRATION_VERSION: &str = "20231114233125";
+
+/// ## Default settings inserted into a freshly migrated database
 pub const SQL_DECLARATION: &str = {
     use constcat::concat;
 
@@ -12,7 +21,6 @@ pub const SQL_DECLARATION: &str = {
 ("socket", "0.0.0.0:443")"#;
     
     concat!(
-        include_str!("../migrations/2023-11-14-233125_init/up.sql"), // Raw sql
         "INSERT INTO key_value (key, value) VALUES ", // Template just to not repeat myself
         if cfg!(feature = "ssl") { SSL_KEY_VALUES } else { "" },
         if cfg!(not(feature = "ssl")) { NO_SSL_KEY_VALUES } else { "" },
@@ -34,6 +42,46 @@ pub fn establish_connection(url: String) -> Pool {
         .unwrap_or_else(|err| exit_with_error!("Couldn't create a db connection pool.:\n{}", err))
 }
 
+/// ## Brings the database schema up to date by running all pending migrations
+pub fn run_migrations(conn: &mut Conn) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    use diesel::{connection::SimpleConnection, sql_types::Bool};
+
+    let is_tracked = diesel::select(diesel::dsl::sql::<Bool>(
+        "EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = '__diesel_schema_migrations')"))
+        .get_result::<bool>(conn)?;
+    let has_users = diesel::select(diesel::dsl::sql::<Bool>(
+        "EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'users')"))
+        .get_result::<bool>(conn)?;
+
+    // Mark the init migration as done on databases that were created without migrations
+    if ! is_tracked && has_users {
+        conn.batch_execute(&format!(
+            "CREATE TABLE __diesel_schema_migrations (
+                version VARCHAR(50) PRIMARY KEY NOT NULL,
+                run_on TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+            );
+            INSERT INTO __diesel_schema_migrations (version) VALUES ('{}');",
+            INIT_MIGRATION_VERSION,
+        ))?;
+    }
+
+    conn.run_pending_migrations(MIGRATIONS)?;
+    Ok(())
+}
+
+/// ## A freshly migrated database in a temporary file, for the tests
+/// The name keeps the tests running in parallel apart, the caller removes the file at the end
+#[cfg(test)]
+pub fn test_pool(name: &str) -> (Pool, std::path::PathBuf) {
+    let path = std::env::temp_dir().join(format!("cookbook_test_{}_{}.db", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    std::fs::File::create(&path).unwrap();
+
+    let pool = establish_connection(format!("sqlite://{}", path.display()));
+    run_migrations(&mut pool.get().unwrap()).unwrap();
+    (pool, path)
+}
+
 
 // my onw prelude
 pub mod prelude {
@@ -42,9 +90,19 @@ pub mod prelude {
     pub use diesel::prelude::*;
 
     pub use schema::ammounts::dsl as ammounts_dsl;
+    pub use schema::api_tokens::dsl as api_tokens_dsl;
+    pub use schema::audit_events::dsl as audit_events_dsl;
     pub use schema::ingredients::dsl as ingredients_dsl;
+    pub use schema::invitations::dsl as invitations_dsl;
+    pub use schema::jwt_keys::dsl as jwt_keys_dsl;
     pub use schema::key_value::dsl as key_value_dsl;
+    pub use schema::login_attempts::dsl as login_attempts_dsl;
+    pub use schema::password_resets::dsl as password_resets_dsl;
+    pub use schema::rate_limit_policies::dsl as rate_limit_policies_dsl;
     pub use schema::recipes::dsl as recipes_dsl;
+    pub use schema::recovery_codes::dsl as recovery_codes_dsl;
+    pub use schema::tokens::dsl as tokens_dsl;
+    pub use schema::totp::dsl as totp_dsl;
     pub use schema::users::dsl as users_dsl;
 }
 
@@ -71,7 +129,6 @@ pub mod key_value {
         Ok(())
     }
 
-    #[allow(dead_code)]
     pub fn remove(conn: &mut Conn, key: &str) -> Result<(), diesel::result::Error> {
         diesel::delete(key_value_dsl::key_value.filter(key_value_dsl::key.eq(key)))
             .execute(conn)?;
@@ -79,3 +136,816 @@ pub mod key_value {
     }
 }
 
+
+
+pub mod users {
+    use crate::{auth::{self, Role}, db::Conn, models, validating};
+    use super::{
+        ammounts_dsl, api_tokens_dsl, invitations_dsl, login_attempts_dsl, password_resets_dsl,
+        recipes_dsl, recovery_codes_dsl, tokens_dsl, totp_dsl, users_dsl,
+    };
+    use chrono::Utc;
+    use diesel::prelude::*;
+    use diesel::result::{DatabaseErrorKind, Error as DieselError};
+
+    #[derive(Debug)]
+    pub enum CreateError {
+        /// The username, password or email break some rules
+        Invalid(Vec<validating::Violation>),
+        /// The username or the email is already taken
+        Exists,
+        Database(DieselError),
+    }
+
+    /// ## Validates and creates a new user
+    /// Shared by the setup commands and the admin api
+    pub fn create(
+        conn: &mut Conn,
+        policy: &validating::CredentialPolicy,
+        username: &str,
+        password: &str,
+        role: Role,
+        email: Option<&str>,
+    ) -> Result<(), CreateError> {
+        let mut violations = policy.check(username, password);
+        if email.is_some_and(|val| ! validating::is_valid_email(val)) {
+            violations.push(validating::Violation::InvalidEmail);
+        }
+        if ! violations.is_empty() {
+            return Err(CreateError::Invalid(violations));
+        }
+
+        let result = diesel::insert_into(users_dsl::users)
+            .values(models::User {
+                username: username.to_owned(),
+                password_hash: auth::hash_password(password),
+                role: role.to_string(),
+                email: email.map(str::to_owned),
+                disabled_at: None,
+                disabled_reason: None,
+            })
+            .execute(conn);
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => Err(CreateError::Exists),
+            Err(err) => Err(CreateError::Database(err)),
+        }
+    }
+
+    /// Gets a page of users ordered by their username
+    /// ### Returns
+    /// The users along with the total number of users
+    pub fn get_page(conn: &mut Conn, offset: i64, limit: i64) -> Result<(Vec<models::User>, i64), DieselError> {
+        conn.transaction(|conn| {
+            let users = users_dsl::users
+                .order(users_dsl::username.asc())
+                .offset(offset)
+                .limit(limit)
+                .load(conn)?;
+            let total = users_dsl::users
+                .count()
+                .get_result(conn)?;
+            Ok((users, total))
+        })
+    }
+
+    /// ### Returns
+    /// The number of updated users, so 0 if it wasn't found
+    pub fn set_role(conn: &mut Conn, username: &str, role: Role) -> Result<usize, DieselError> {
+        diesel::update(users_dsl::users.find(username))
+            .set(users_dsl::role.eq(role.to_string()))
+            .execute(conn)
+    }
+
+    /// Disabled users can't log in and their tokens stop working
+    /// ### Returns
+    /// The number of disabled users, so 0 if it wasn't found
+    pub fn disable(conn: &mut Conn, username: &str, reason: Option<&str>) -> Result<usize, DieselError> {
+        diesel::update(users_dsl::users.find(username))
+            .set((
+                users_dsl::disabled_at.eq(Utc::now().timestamp()),
+                users_dsl::disabled_reason.eq(reason),
+            ))
+            .execute(conn)
+    }
+
+    /// ### Returns
+    /// The number of enabled users, so 0 if it wasn't found
+    pub fn enable(conn: &mut Conn, username: &str) -> Result<usize, DieselError> {
+        diesel::update(users_dsl::users.find(username))
+            .set((
+                users_dsl::disabled_at.eq(None::<i64>),
+                users_dsl::disabled_reason.eq(None::<String>),
+            ))
+            .execute(conn)
+    }
+
+    /// Checked on every request, so a changed role or a disabled account applies to the tokens right away
+    /// ### Returns
+    /// None if the user is disabled or doesn't exist anymore
+    pub fn current_role(conn: &mut Conn, username: &str) -> Result<Option<Role>, DieselError> {
+        let user: Option<(String, Option<i64>)> = users_dsl::users
+            .select((users_dsl::role, users_dsl::disabled_at))
+            .find(username)
+            .first(conn)
+            .optional()?;
+        Ok(match user {
+            Some((role, None)) => Some(role.parse().unwrap_or_default()),
+            _ => None,
+        })
+    }
+
+    /// Checks if the user is the only admin, the server refuses to start without one
+    pub fn is_last_admin(conn: &mut Conn, username: &str) -> Result<bool, diesel::result::Error> {
+        let admins: Vec<String> = users_dsl::users
+            .select(users_dsl::username)
+            .filter(users_dsl::role.eq(Role::Admin.to_string()))
+            .load(conn)?;
+        Ok(admins == [username])
+    }
+
+    /// Deletes the user, their recipes are either given to `transfer_to` or deleted along with them.
+    /// Tokens, sessions, reset links and the second factor go along with them.
+    /// The bundled sqlite enforces the foreign keys, so they would cascade anyway,
+    /// the explicit deletes keep it that way when built against a system sqlite that doesn't enforce them.
+    /// Sessions in the memory token store aren't in the database, the caller has to invalidate them
+    /// ### Returns
+    /// false if the user to transfer the recipes to doesn't exist
+    pub fn delete(conn: &mut Conn, username: &str, transfer_to: Option<&str>) -> Result<bool, diesel::result::Error> {
+        conn.transaction(|conn| {
+            match transfer_to {
+                Some(new_owner) => {
+                    let exists: bool = diesel::select(diesel::dsl::exists(users_dsl::users.find(new_owner)))
+                        .get_result(conn)?;
+                    if ! exists {
+                        return Ok(false);
+                    }
+                    diesel::update(recipes_dsl::recipes.filter(recipes_dsl::owner.eq(username)))
+                        .set(recipes_dsl::owner.eq(new_owner))
+                        .execute(conn)?;
+                }
+                None => {
+                    // The ammounts don't cascade, so they have to go first
+                    let recipes = recipes_dsl::recipes
+                        .select(recipes_dsl::name)
+                        .filter(recipes_dsl::owner.eq(username));
+                    diesel::delete(ammounts_dsl::ammounts.filter(ammounts_dsl::recipe.eq_any(recipes)))
+                        .execute(conn)?;
+                    diesel::delete(recipes_dsl::recipes.filter(recipes_dsl::owner.eq(username)))
+                        .execute(conn)?;
+                }
+            }
+
+            diesel::delete(login_attempts_dsl::login_attempts.filter(login_attempts_dsl::username.eq(username)))
+                .execute(conn)?;
+            diesel::delete(tokens_dsl::tokens.filter(tokens_dsl::username.eq(username)))
+                .execute(conn)?;
+            diesel::delete(api_tokens_dsl::api_tokens.filter(api_tokens_dsl::username.eq(username)))
+                .execute(conn)?;
+            diesel::delete(password_resets_dsl::password_resets.filter(password_resets_dsl::username.eq(username)))
+                .execute(conn)?;
+            diesel::delete(totp_dsl::totp.find(username))
+                .execute(conn)?;
+            diesel::delete(recovery_codes_dsl::recovery_codes.filter(recovery_codes_dsl::username.eq(username)))
+                .execute(conn)?;
+            // The invitations stay usable, they just lose their creator
+            diesel::update(invitations_dsl::invitations.filter(invitations_dsl::created_by.eq(username)))
+                .set(invitations_dsl::created_by.eq(None::<String>))
+                .execute(conn)?;
+            diesel::delete(users_dsl::users.find(username))
+                .execute(conn)?;
+            Ok(true)
+        })
+    }
+}
+
+
+pub mod ingredients {
+    use crate::db::Conn;
+    use super::{ammounts_dsl, ingredients_dsl};
+    use diesel::prelude::*;
+
+    pub fn get_all(conn: &mut Conn) -> Result<Vec<String>, diesel::result::Error> {
+        ingredients_dsl::ingredients
+            .select(ingredients_dsl::name)
+            .order(ingredients_dsl::name.asc())
+            .load(conn)
+    }
+
+    /// Checks if any recipe uses the ingredient
+    pub fn is_used(conn: &mut Conn, name: &str) -> Result<bool, diesel::result::Error> {
+        diesel::select(diesel::dsl::exists(
+            ammounts_dsl::ammounts.filter(ammounts_dsl::kind.eq(name))
+        ))
+            .get_result(conn)
+    }
+
+    /// Renames the ingredient along with all of its uses in recipes
+    /// ### Returns
+    /// The number of renamed ingredients, so 0 if it wasn't found
+    pub fn rename(conn: &mut Conn, name: &str, new_name: &str) -> Result<usize, diesel::result::Error> {
+        conn.transaction(|conn| {
+            // The ammounts point to the old name until they are updated, so check the keys on commit
+            diesel::sql_query("PRAGMA defer_foreign_keys = ON").execute(conn)?;
+            let renamed = diesel::update(ingredients_dsl::ingredients.find(name))
+                .set(ingredients_dsl::name.eq(new_name))
+                .execute(conn)?;
+            diesel::update(ammounts_dsl::ammounts.filter(ammounts_dsl::kind.eq(name)))
+                .set(ammounts_dsl::kind.eq(new_name))
+                .execute(conn)?;
+            Ok(renamed)
+        })
+    }
+}
+
+
+pub mod invitations {
+    use crate::{auth::token_storage::hash_token, db::Conn, models};
+    use super::invitations_dsl;
+    use chrono::{Duration, Utc};
+    use diesel::prelude::*;
+    use rand::Rng;
+
+    /// Creates a new invitation code that can be used `uses` times
+    /// ### Returns
+    /// The code itself, only its hash gets stored so it can't be shown again
+    pub fn create(conn: &mut Conn, created_by: Option<&str>, uses: i32, valid_for: Duration) -> Result<String, diesel::result::Error> {
+        let code: String = {
+            let mut rng = rand::thread_rng();
+            (0..16)
+                .map(|_| rng.sample(rand::distributions::Alphanumeric) as char)
+                .collect()
+        };
+
+        let now = Utc::now();
+        diesel::insert_into(invitations_dsl::invitations)
+            .values(&models::InvitationInsertable {
+                code_hash: hash_token(&code),
+                created_by: created_by.map(str::to_owned),
+                uses_left: uses,
+                created_at: now.timestamp(),
+                expiration: (now + valid_for).timestamp(),
+            })
+            .execute(conn)?;
+        Ok(code)
+    }
+
+    pub fn get_all(conn: &mut Conn) -> Result<Vec<models::Invitation>, diesel::result::Error> {
+        invitations_dsl::invitations
+            .select((
+                invitations_dsl::id,
+                invitations_dsl::created_by,
+                invitations_dsl::uses_left,
+                invitations_dsl::created_at,
+                invitations_dsl::expiration,
+            ))
+            .order(invitations_dsl::id.asc())
+            .load(conn)
+    }
+
+    /// Uses up one use of the code
+    /// ### Returns
+    /// false if the code doesn't exist, has expired or was already used up
+    pub fn consume(conn: &mut Conn, code: &str) -> Result<bool, diesel::result::Error> {
+        let consumed = diesel::update(invitations_dsl::invitations
+            .filter(invitations_dsl::code_hash.eq(hash_token(code)))
+            .filter(invitations_dsl::uses_left.gt(0))
+            .filter(invitations_dsl::expiration.gt(Utc::now().timestamp())))
+            .set(invitations_dsl::uses_left.eq(invitations_dsl::uses_left - 1))
+            .execute(conn)?;
+        Ok(consumed != 0)
+    }
+
+    /// ### Returns
+    /// The number of revoked invitations, so 0 if it wasn't found
+    pub fn revoke(conn: &mut Conn, id: i32) -> Result<usize, diesel::result::Error> {
+        diesel::delete(invitations_dsl::invitations.find(id))
+            .execute(conn)
+    }
+}
+
+
+pub mod jwt_keys {
+    use crate::{db::Conn, models};
+    use super::jwt_keys_dsl;
+    use chrono::{Duration, Utc};
+    use diesel::prelude::*;
+    use rand::Rng;
+
+    /// Adds a new inactive key
+    /// ### Returns
+    /// The kid of the new key
+    pub fn add(conn: &mut Conn, secret: &str) -> Result<String, diesel::result::Error> {
+        let kid: String = {
+            let mut rng = rand::thread_rng();
+            (0..16)
+                .map(|_| rng.sample(rand::distributions::Alphanumeric) as char)
+                .collect()
+        };
+
+        diesel::insert_into(jwt_keys_dsl::jwt_keys)
+            .values(&models::JwtKey {
+                kid: kid.clone(),
+                secret: secret.to_owned(),
+                status: "inactive".to_owned(),
+                created_at: Utc::now().timestamp(),
+                verify_until: None,
+                algorithm: "HS256".to_owned(),
+            })
+            .execute(conn)?;
+        Ok(kid)
+    }
+
+    /// Keeps the public key of a replaced asymmetric key, so it verifies the tokens signed with it for the `grace` period
+    pub fn add_retired_public(conn: &mut Conn, kid: &str, algorithm: &str, public_pem: &str, grace: Duration) -> Result<usize, diesel::result::Error> {
+        let now = Utc::now();
+        diesel::replace_into(jwt_keys_dsl::jwt_keys)
+            .values(&models::JwtKey {
+                kid: kid.to_owned(),
+                secret: public_pem.to_owned(),
+                status: "retired".to_owned(),
+                created_at: now.timestamp(),
+                verify_until: Some((now + grace).timestamp()),
+                algorithm: algorithm.to_owned(),
+            })
+            .execute(conn)
+    }
+
+    pub fn get(conn: &mut Conn, kid: &str) -> Result<Option<models::JwtKey>, diesel::result::Error> {
+        jwt_keys_dsl::jwt_keys
+            .find(kid)
+            .first(conn)
+            .optional()
+    }
+
+    pub fn get_all(conn: &mut Conn) -> Result<Vec<models::JwtKey>, diesel::result::Error> {
+        jwt_keys_dsl::jwt_keys
+            .order(jwt_keys_dsl::created_at.asc())
+            .load(conn)
+    }
+
+    /// Gets all the keys that can still verify tokens, so everything except retired keys past their grace period
+    pub fn get_usable(conn: &mut Conn) -> Result<Vec<models::JwtKey>, diesel::result::Error> {
+        jwt_keys_dsl::jwt_keys
+            .filter(jwt_keys_dsl::status.ne("retired")
+                .or(jwt_keys_dsl::verify_until.gt(Utc::now().timestamp())))
+            .order(jwt_keys_dsl::created_at.asc())
+            .load(conn)
+    }
+
+    /// Makes the key the one used for signing new tokens.
+    /// The previously active key gets retired, but keeps verifying tokens for the `grace` period
+    /// ### Returns
+    /// The number of activated keys, so 0 if it wasn't found or isn't a HS256 key
+    pub fn activate(conn: &mut Conn, kid: &str, grace: Duration) -> Result<usize, diesel::result::Error> {
+        conn.transaction(|conn| {
+            match get(conn, kid)? {
+                Some(key) if key.algorithm == "HS256" => {},
+                _ => return Ok(0),
+            }
+
+            diesel::update(jwt_keys_dsl::jwt_keys
+                .filter(jwt_keys_dsl::status.eq("active"))
+                .filter(jwt_keys_dsl::kid.ne(kid)))
+                .set((
+                    jwt_keys_dsl::status.eq("retired"),
+                    jwt_keys_dsl::verify_until.eq((Utc::now() + grace).timestamp()),
+                ))
+                .execute(conn)?;
+            diesel::update(jwt_keys_dsl::jwt_keys.find(kid))
+                .set((
+                    jwt_keys_dsl::status.eq("active"),
+                    jwt_keys_dsl::verify_until.eq(None::<i64>),
+                ))
+                .execute(conn)
+        })
+    }
+
+    /// Retires the key right away, tokens signed with it stop being valid.
+    /// The active key can't be retired, activate a different one instead
+    /// ### Returns
+    /// The number of retired keys, so 0 if it wasn't found or is active
+    pub fn retire(conn: &mut Conn, kid: &str) -> Result<usize, diesel::result::Error> {
+        diesel::update(jwt_keys_dsl::jwt_keys
+            .filter(jwt_keys_dsl::kid.eq(kid))
+            .filter(jwt_keys_dsl::status.ne("active")))
+            .set((
+                jwt_keys_dsl::status.eq("retired"),
+                jwt_keys_dsl::verify_until.eq(Utc::now().timestamp()),
+            ))
+            .execute(conn)
+    }
+}
+
+
+pub mod api_tokens {
+    use crate::{auth::{token_storage::hash_token, Scope}, db::Conn, models};
+    use super::{api_tokens_dsl, users_dsl};
+    use chrono::{DateTime, Utc};
+    use diesel::prelude::*;
+    use rand::Rng;
+
+    /// Prefix that tells personal access tokens apart from jwt
+    pub const TOKEN_PREFIX: &str = "cbpat_";
+
+    const SELECTION: (
+        api_tokens_dsl::id,
+        api_tokens_dsl::username,
+        api_tokens_dsl::name,
+        api_tokens_dsl::scopes,
+        api_tokens_dsl::created_at,
+        api_tokens_dsl::expiration,
+    ) = (
+        api_tokens_dsl::id,
+        api_tokens_dsl::username,
+        api_tokens_dsl::name,
+        api_tokens_dsl::scopes,
+        api_tokens_dsl::created_at,
+        api_tokens_dsl::expiration,
+    );
+
+    /// Creates a new personal access token, it never expires if no expiration is specified
+    /// ### Returns
+    /// The id and the token itself, only its hash gets stored so it can't be shown again
+    pub fn create(
+        conn: &mut Conn,
+        username: &str,
+        name: &str,
+        scopes: &[Scope],
+        expiration: Option<DateTime<Utc>>,
+    ) -> Result<(i32, String), diesel::result::Error> {
+        let token: String = {
+            let mut rng = rand::thread_rng();
+            let random: String = (0..40)
+                .map(|_| rng.sample(rand::distributions::Alphanumeric) as char)
+                .collect();
+            format!("{}{}", TOKEN_PREFIX, random)
+        };
+
+        let scopes = scopes.iter()
+            .map(|scope| scope.to_string())
+            .collect::<Vec<String>>()
+            .join(" ");
+
+        let token_hash = hash_token(&token);
+        let id = conn.transaction(|conn| {
+            diesel::insert_into(api_tokens_dsl::api_tokens)
+                .values(&models::ApiTokenInsertable {
+                    token_hash: token_hash.clone(),
+                    username: username.to_owned(),
+                    name: name.to_owned(),
+                    scopes,
+                    created_at: Utc::now().timestamp(),
+                    expiration: expiration.map(|val| val.timestamp()),
+                })
+                .execute(conn)?;
+            api_tokens_dsl::api_tokens
+                .select(api_tokens_dsl::id)
+                .filter(api_tokens_dsl::token_hash.eq(&token_hash))
+                .first(conn)
+        })?;
+        Ok((id, token))
+    }
+
+    pub fn get_by_user(conn: &mut Conn, username: &str) -> Result<Vec<models::ApiToken>, diesel::result::Error> {
+        api_tokens_dsl::api_tokens
+            .select(SELECTION)
+            .filter(api_tokens_dsl::username.eq(username))
+            .order(api_tokens_dsl::id.asc())
+            .load(conn)
+    }
+
+    /// Finds the token along with the current role of its owner
+    /// ### Returns
+    /// None if the token doesn't exist, has expired or its owner is disabled
+    pub fn authenticate(conn: &mut Conn, token: &str) -> Result<Option<(models::ApiToken, String)>, diesel::result::Error> {
+        api_tokens_dsl::api_tokens
+            .inner_join(users_dsl::users)
+            .select((SELECTION, users_dsl::role))
+            .filter(api_tokens_dsl::token_hash.eq(hash_token(token)))
+            .filter(users_dsl::disabled_at.is_null())
+            .filter(api_tokens_dsl::expiration.is_null()
+                .or(api_tokens_dsl::expiration.gt(Utc::now().timestamp())))
+            .first(conn)
+            .optional()
+    }
+
+    /// Revokes all the tokens of the user
+    pub fn revoke_all(conn: &mut Conn, username: &str) -> Result<usize, diesel::result::Error> {
+        diesel::delete(api_tokens_dsl::api_tokens.filter(api_tokens_dsl::username.eq(username)))
+            .execute(conn)
+    }
+
+    /// Only the owner can revoke their token
+    /// ### Returns
+    /// The number of revoked tokens, so 0 if it wasn't found
+    pub fn revoke(conn: &mut Conn, username: &str, id: i32) -> Result<usize, diesel::result::Error> {
+        diesel::delete(api_tokens_dsl::api_tokens
+            .filter(api_tokens_dsl::id.eq(id))
+            .filter(api_tokens_dsl::username.eq(username)))
+            .execute(conn)
+    }
+}
+
+
+pub mod totp {
+    use crate::{auth, db::Conn, models};
+    use super::{recovery_codes_dsl, totp_dsl};
+    use diesel::prelude::*;
+
+    pub fn get(conn: &mut Conn, username: &str) -> Result<Option<models::Totp>, diesel::result::Error> {
+        totp_dsl::totp
+            .filter(totp_dsl::username.eq(username))
+            .first(conn)
+            .optional()
+    }
+
+    /// Starts a new enrollment, replacing one that wasn't confirmed
+    pub fn set_secret(conn: &mut Conn, username: &str, secret: &str) -> Result<usize, diesel::result::Error> {
+        diesel::replace_into(totp_dsl::totp)
+            .values(&models::Totp {
+                username: username.to_owned(),
+                secret: secret.to_owned(),
+                enabled: false,
+                last_step: 0,
+            })
+            .execute(conn)
+    }
+
+    /// Enables the second factor and replaces the recovery codes
+    pub fn enable(conn: &mut Conn, username: &str, last_step: i64, recovery_code_hashes: &[String]) -> Result<(), diesel::result::Error> {
+        conn.transaction(|conn| {
+            diesel::update(totp_dsl::totp.filter(totp_dsl::username.eq(username)))
+                .set((totp_dsl::enabled.eq(true), totp_dsl::last_step.eq(last_step)))
+                .execute(conn)?;
+            diesel::delete(recovery_codes_dsl::recovery_codes.filter(recovery_codes_dsl::username.eq(username)))
+                .execute(conn)?;
+            let rows: Vec<_> = recovery_code_hashes.iter()
+                .map(|hash| (recovery_codes_dsl::username.eq(username), recovery_codes_dsl::code_hash.eq(hash)))
+                .collect();
+            diesel::insert_into(recovery_codes_dsl::recovery_codes)
+                .values(&rows)
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    pub fn set_last_step(conn: &mut Conn, username: &str, last_step: i64) -> Result<usize, diesel::result::Error> {
+        diesel::update(totp_dsl::totp.filter(totp_dsl::username.eq(username)))
+            .set(totp_dsl::last_step.eq(last_step))
+            .execute(conn)
+    }
+
+    /// Disables the second factor and removes the recovery codes
+    /// ### Returns
+    /// The number of removed totp rows, so 0 if the user didn't have any
+    pub fn remove(conn: &mut Conn, username: &str) -> Result<usize, diesel::result::Error> {
+        conn.transaction(|conn| {
+            diesel::delete(recovery_codes_dsl::recovery_codes.filter(recovery_codes_dsl::username.eq(username)))
+                .execute(conn)?;
+            diesel::delete(totp_dsl::totp.filter(totp_dsl::username.eq(username)))
+                .execute(conn)
+        })
+    }
+
+    pub fn count_recovery_codes(conn: &mut Conn, username: &str) -> Result<i64, diesel::result::Error> {
+        recovery_codes_dsl::recovery_codes
+            .filter(recovery_codes_dsl::username.eq(username))
+            .count()
+            .get_result(conn)
+    }
+
+    /// Recovery codes are single use, the matching one gets deleted
+    /// ### Returns
+    /// If the code was valid
+    pub fn use_recovery_code(conn: &mut Conn, username: &str, code: &str) -> Result<bool, diesel::result::Error> {
+        let codes: Vec<(i32, String)> = recovery_codes_dsl::recovery_codes
+            .select((recovery_codes_dsl::id, recovery_codes_dsl::code_hash))
+            .filter(recovery_codes_dsl::username.eq(username))
+            .load(conn)?;
+
+        let code = code.trim().to_lowercase();
+        let id = match codes.iter().find(|(_, hash)| auth::verify_password(&code, hash)) {
+            Some((id, _)) => *id,
+            None => return Ok(false),
+        };
+        let deleted = diesel::delete(recovery_codes_dsl::recovery_codes.filter(recovery_codes_dsl::id.eq(id)))
+            .execute(conn)?;
+        // Another request could have used the same code in the meantime
+        Ok(deleted == 1)
+    }
+}
+
+
+pub mod login_attempts {
+    use crate::{auth::lockout, db::Conn, models};
+    use super::login_attempts_dsl;
+    use chrono::Utc;
+    use diesel::prelude::*;
+
+    pub fn get(conn: &mut Conn, username: &str) -> Result<Option<models::LoginAttempt>, diesel::result::Error> {
+        login_attempts_dsl::login_attempts
+            .filter(login_attempts_dsl::username.eq(username))
+            .first(conn)
+            .optional()
+    }
+
+    pub fn get_all(conn: &mut Conn) -> Result<Vec<models::LoginAttempt>, diesel::result::Error> {
+        login_attempts_dsl::login_attempts
+            .order(login_attempts_dsl::last_failed.desc())
+            .load(conn)
+    }
+
+    /// Counts a failed log in, failures older than the reset window are forgotten first
+    pub fn record_failure(conn: &mut Conn, username: &str) -> Result<usize, diesel::result::Error> {
+        let now = Utc::now().timestamp();
+
+        // Immediate so concurrent failures can't overwrite each others count
+        conn.immediate_transaction(|conn| {
+            let previous: Option<models::LoginAttempt> = login_attempts_dsl::login_attempts
+                .filter(login_attempts_dsl::username.eq(username))
+                .first(conn)
+                .optional()?;
+            let failed_count = match previous {
+                Some(val) if now - val.last_failed < lockout::RESET_SECONDS => val.failed_count + 1,
+                _ => 1,
+            };
+            diesel::replace_into(login_attempts_dsl::login_attempts)
+                .values(&models::LoginAttempt {
+                    username: username.to_owned(),
+                    failed_count,
+                    last_failed: now,
+                })
+                .execute(conn)
+        })
+    }
+
+    /// Called after a successful log in, or by an admin to unlock the account
+    /// ### Returns
+    /// The number of removed entries, so 0 if there were no failed attempts
+    pub fn clear(conn: &mut Conn, username: &str) -> Result<usize, diesel::result::Error> {
+        diesel::delete(login_attempts_dsl::login_attempts.filter(login_attempts_dsl::username.eq(username)))
+            .execute(conn)
+    }
+
+    /// Removes the entries that are past the reset window
+    pub fn clean(conn: &mut Conn) -> Result<usize, diesel::result::Error> {
+        let cutoff = Utc::now().timestamp() - lockout::RESET_SECONDS;
+        diesel::delete(login_attempts_dsl::login_attempts.filter(login_attempts_dsl::last_failed.le(cutoff)))
+            .execute(conn)
+    }
+}
+
+
+pub mod rate_limit_policies {
+    use crate::{db::Conn, models};
+    use super::rate_limit_policies_dsl;
+    use diesel::prelude::*;
+
+    pub fn get_all(conn: &mut Conn) -> Result<Vec<models::RateLimitPolicy>, diesel::result::Error> {
+        rate_limit_policies_dsl::rate_limit_policies
+            .order(rate_limit_policies_dsl::name.asc())
+            .load(conn)
+    }
+
+    /// ### Returns
+    /// The number of updated policies, so 0 if it wasn't found
+    pub fn update(conn: &mut Conn, policy: &models::RateLimitPolicy) -> Result<usize, diesel::result::Error> {
+        diesel::update(rate_limit_policies_dsl::rate_limit_policies.find(&policy.name))
+            .set(policy)
+            .execute(conn)
+    }
+}
+
+
+pub mod password_resets {
+    use crate::{auth::token_storage::hash_token, db::Conn};
+    use super::password_resets_dsl;
+    use chrono::{Duration, Utc};
+    use diesel::prelude::*;
+    use rand::Rng;
+
+    /// Creates a new reset token, the previous ones of the user stop working
+    /// ### Returns
+    /// The token itself, only its hash gets stored
+    pub fn create(conn: &mut Conn, username: &str, valid_for: Duration) -> Result<String, diesel::result::Error> {
+        let token: String = {
+            let mut rng = rand::thread_rng();
+            (0..40)
+                .map(|_| rng.sample(rand::distributions::Alphanumeric) as char)
+                .collect()
+        };
+
+        conn.transaction(|conn| {
+            diesel::delete(password_resets_dsl::password_resets.filter(password_resets_dsl::username.eq(username)))
+                .execute(conn)?;
+            diesel::insert_into(password_resets_dsl::password_resets)
+                .values((
+                    password_resets_dsl::token_hash.eq(hash_token(&token)),
+                    password_resets_dsl::username.eq(username),
+                    password_resets_dsl::expiration.eq((Utc::now() + valid_for).timestamp()),
+                ))
+                .execute(conn)
+        })?;
+        Ok(token)
+    }
+
+    /// Uses up the token
+    /// ### Returns
+    /// The username the token was issued for, None if it doesn't exist or has expired
+    pub fn consume(conn: &mut Conn, token: &str) -> Result<Option<String>, diesel::result::Error> {
+        let token_hash = hash_token(token);
+        conn.transaction(|conn| {
+            let username: Option<String> = password_resets_dsl::password_resets
+                .select(password_resets_dsl::username)
+                .filter(password_resets_dsl::token_hash.eq(&token_hash))
+                .filter(password_resets_dsl::expiration.gt(Utc::now().timestamp()))
+                .first(conn)
+                .optional()?;
+            diesel::delete(password_resets_dsl::password_resets.filter(password_resets_dsl::token_hash.eq(&token_hash)))
+                .execute(conn)?;
+            Ok(username)
+        })
+    }
+
+    /// Removes all the tokens of the user
+    pub fn remove(conn: &mut Conn, username: &str) -> Result<usize, diesel::result::Error> {
+        diesel::delete(password_resets_dsl::password_resets.filter(password_resets_dsl::username.eq(username)))
+            .execute(conn)
+    }
+
+    /// Removes the expired tokens
+    pub fn clean(conn: &mut Conn) -> Result<usize, diesel::result::Error> {
+        diesel::delete(password_resets_dsl::password_resets.filter(password_resets_dsl::expiration.le(Utc::now().timestamp())))
+            .execute(conn)
+    }
+}
+
+
+pub mod audit_events {
+    use crate::{db::Conn, models, schema};
+    use super::audit_events_dsl;
+    use diesel::prelude::*;
+    use diesel::sqlite::Sqlite;
+
+    /// ## Which events to return, all the set fields have to match
+    #[derive(Debug, Clone, Default)]
+    pub struct Filter {
+        pub actor: Option<String>,
+        pub action: Option<String>,
+        pub target: Option<String>,
+        /// Unix timestamp, inclusive
+        pub since: Option<i64>,
+        /// Unix timestamp, inclusive
+        pub until: Option<i64>,
+    }
+
+    fn filtered(filter: &Filter) -> schema::audit_events::BoxedQuery<'_, Sqlite> {
+        let mut query = audit_events_dsl::audit_events.into_boxed();
+        if let Some(val) = &filter.actor {
+            query = query.filter(audit_events_dsl::actor.eq(val));
+        }
+        if let Some(val) = &filter.action {
+            query = query.filter(audit_events_dsl::action.eq(val));
+        }
+        if let Some(val) = &filter.target {
+            query = query.filter(audit_events_dsl::target.eq(val));
+        }
+        if let Some(val) = filter.since {
+            query = query.filter(audit_events_dsl::created_at.ge(val));
+        }
+        if let Some(val) = filter.until {
+            query = query.filter(audit_events_dsl::created_at.le(val));
+        }
+        query
+    }
+
+    pub fn record(conn: &mut Conn, event: &models::AuditEventInsertable) -> Result<usize, diesel::result::Error> {
+        diesel::insert_into(audit_events_dsl::audit_events)
+            .values(event)
+            .execute(conn)
+    }
+
+    /// Newest first
+    /// ### Returns
+    /// The events on the page along with the number of all the matching events
+    pub fn get_page(conn: &mut Conn, filter: &Filter, offset: i64, limit: i64) -> Result<(Vec<models::AuditEvent>, i64), diesel::result::Error> {
+        let total = filtered(filter)
+            .count()
+            .get_result(conn)?;
+        let events = filtered(filter)
+            .order(audit_events_dsl::id.desc())
+            .offset(offset)
+            .limit(limit)
+            .load(conn)?;
+        Ok((events, total))
+    }
+
+    /// Oldest first, used for exporting
+    pub fn get_all(conn: &mut Conn, filter: &Filter) -> Result<Vec<models::AuditEvent>, diesel::result::Error> {
+        filtered(filter)
+            .order(audit_events_dsl::id.asc())
+            .load(conn)
+    }
+}
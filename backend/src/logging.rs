@@ -0,0 +1,91 @@
+//! ## Leveled logs of the server
+//!
+//! Written to stderr, so the output of the setup commands stays clean.
+//! Tokens, passwords and secrets never get logged, the types holding them leave them out of their Debug output.
+//! The only exception is the stdout mail transport, meant for testing, which prints whole mails to stdout
+
+use std::str::FromStr;
+use tracing_subscriber::{
+    EnvFilter, Registry,
+    fmt::format::FmtSpan,
+    layer::SubscriberExt,
+    reload,
+    util::SubscriberInitExt,
+};
+
+/// Used when neither CB_LOG_LEVEL nor the "-l" flag is set
+pub const DEFAULT_LEVEL: &str = "info";
+
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable, multiple lines per event
+    #[default]
+    Pretty,
+    /// One json object per line
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format \"{}\", one of: pretty, json", s)),
+        }
+    }
+}
+
+
+/// ## Changes the level after the logging was set up
+pub struct LevelHandle(reload::Handle<EnvFilter, Registry>);
+
+impl LevelHandle {
+    /// Takes a level like "debug" or a filter like "info,backend=trace"
+    pub fn set_level(&self, level: &str) -> Result<(), String> {
+        let filter = EnvFilter::try_new(level)
+            .map_err(|err| format!("Invalid log level \"{}\": {}", level, err))?;
+        self.0.reload(filter).map_err(|err| err.to_string())
+    }
+}
+
+
+/// Can only be called once
+pub fn init(level: &str, format: LogFormat) -> Result<LevelHandle, String> {
+    use std::io::IsTerminal;
+
+    let filter = EnvFilter::try_new(level)
+        .map_err(|err| format!("Invalid log level \"{}\": {}", level, err))?;
+    let (filter, handle) = reload::Layer::new(filter);
+
+    // Request spans are logged once they close, with their status and duration
+    let ansi = std::io::stderr().is_terminal();
+    let (pretty, json) = match format {
+        LogFormat::Pretty => (
+            Some(tracing_subscriber::fmt::layer()
+                .pretty()
+                .with_ansi(ansi)
+                .with_writer(std::io::stderr)
+                .with_span_events(FmtSpan::CLOSE)),
+            None,
+        ),
+        LogFormat::Json => (
+            None,
+            Some(tracing_subscriber::fmt::layer()
+                .json()
+                .with_writer(std::io::stderr)
+                .with_span_events(FmtSpan::CLOSE)),
+        ),
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(pretty)
+        .with(json)
+        .try_init()
+        .map_err(|err| err.to_string())?;
+
+    Ok(LevelHandle(handle))
+}
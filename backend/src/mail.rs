@@ -0,0 +1,338 @@
+//! ## Sending mail through a configurable transport
+//!
+//! SMTP for real deployments, a file or stdout for testing and installs without a mail server
+
+use crate::db;
+use lettre::Transport;
+use lettre::message::{Message, header::ContentType};
+use lettre::transport::smtp::authentication::Credentials;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::time::Duration;
+
+
+#[derive(Clone)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl std::fmt::Debug for Mail {
+    // Leaves out the body, it can contain password reset links
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Mail")
+            .field("to", &self.to)
+            .field("subject", &self.subject)
+            .finish_non_exhaustive()
+    }
+}
+
+pub trait MailTransport: Send + Sync + std::fmt::Debug {
+    fn send(&self, mail: &Mail) -> Result<(), MailError>;
+}
+
+
+#[derive(Debug)]
+pub enum MailError {
+    Io(io::Error),
+    /// The sender or recipient isn't a valid address
+    Address(lettre::address::AddressError),
+    Message(lettre::error::Error),
+    Smtp(lettre::transport::smtp::Error),
+}
+
+impl std::fmt::Display for MailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MailError::Io(err) => write!(f, "Mail io error: {}", err),
+            MailError::Address(err) => write!(f, "Invalid mail address: {}", err),
+            MailError::Message(err) => write!(f, "Invalid mail: {}", err),
+            MailError::Smtp(err) => write!(f, "Smtp error: {}", err),
+        }
+    }
+}
+
+impl From<io::Error> for MailError {
+    fn from(err: io::Error) -> Self {
+        MailError::Io(err)
+    }
+}
+
+impl From<lettre::address::AddressError> for MailError {
+    fn from(err: lettre::address::AddressError) -> Self {
+        MailError::Address(err)
+    }
+}
+
+impl From<lettre::error::Error> for MailError {
+    fn from(err: lettre::error::Error) -> Self {
+        MailError::Message(err)
+    }
+}
+
+impl From<lettre::transport::smtp::Error> for MailError {
+    fn from(err: lettre::transport::smtp::Error) -> Self {
+        MailError::Smtp(err)
+    }
+}
+
+
+/// ## Loads the transport set with the -s:mail or -s:smtp flag
+/// ### Returns
+/// None if sending mail is turned off
+pub fn load(conn: &mut db::Conn) -> Result<Option<Arc<dyn MailTransport>>, String> {
+    let get = |conn: &mut db::Conn, key: &str| db::key_value::get(conn, key)
+        .map_err(|_| format!("Missing the \"{}\" setting", key));
+
+    let transport = match db::key_value::get(conn, "mail_transport") {
+        Ok(val) => val,
+        Err(_) => return Ok(None),
+    };
+    let from = db::key_value::get(conn, "mail_from").unwrap_or_else(|_| "cookbook@localhost".to_owned());
+
+    let transport: Arc<dyn MailTransport> = match transport.as_str() {
+        "none" => return Ok(None),
+        "stdout" => {
+            tracing::warn!("Mail gets printed to stdout, password reset links included, only use it for testing");
+            Arc::new(StdoutTransport { from })
+        }
+        "file" => Arc::new(FileTransport {
+            from,
+            path: get(conn, "mail_file_path")?,
+        }),
+        "smtp" => {
+            let tls: SmtpTls = get(conn, "smtp_tls")?.parse()
+                .map_err(|_| "Unknown smtp tls mode, expected one of: none, starttls, tls".to_owned())?;
+            let credentials = match (db::key_value::get(conn, "smtp_username"), db::key_value::get(conn, "smtp_password")) {
+                (Ok(username), Ok(password)) => Some((username, password)),
+                _ => None,
+            };
+            Arc::new(SmtpTransport::new(&get(conn, "smtp_address")?, tls, credentials, from)?)
+        }
+        _ => return Err(format!("Unknown mail transport \"{}\", expected one of: none, stdout, file, smtp", transport)),
+    };
+    Ok(Some(transport))
+}
+
+
+/// Builds the message with the headers, lines end with CRLF
+fn build_message(from: &str, mail: &Mail) -> Result<Message, MailError> {
+    let message = Message::builder()
+        .from(from.parse()?)
+        .to(mail.to.parse()?)
+        .subject(mail.subject.as_str())
+        .header(ContentType::TEXT_PLAIN)
+        .body(mail.body.clone())?;
+    Ok(message)
+}
+
+/// The whole message with the headers and plain line endings, for the transports that don't send it
+fn format_message(from: &str, mail: &Mail) -> Result<String, MailError> {
+    let message = build_message(from, mail)?.formatted();
+    Ok(String::from_utf8_lossy(&message).replace("\r\n", "\n"))
+}
+
+
+/// ## Prints the mail instead of sending it
+/// Only meant for testing, the whole mail gets printed, password reset links included
+#[derive(Debug)]
+pub struct StdoutTransport {
+    from: String,
+}
+
+impl MailTransport for StdoutTransport {
+    fn send(&self, mail: &Mail) -> Result<(), MailError> {
+        println!("{}", format_message(&self.from, mail)?);
+        Ok(())
+    }
+}
+
+
+/// ## Appends the mail to a file in the mbox format
+#[derive(Debug)]
+pub struct FileTransport {
+    from: String,
+    path: String,
+}
+
+impl MailTransport for FileTransport {
+    fn send(&self, mail: &Mail) -> Result<(), MailError> {
+        let message = format_message(&self.from, mail)?;
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+
+        // Lines starting with "From " would start a new message
+        let message = message.lines()
+            .map(|line| if line.starts_with("From ") { format!(">{}", line) } else { line.to_owned() })
+            .collect::<Vec<String>>()
+            .join("\n");
+        writeln!(file, "From {} {}\n{}\n", self.from, chrono::Utc::now().format("%a %b %e %T %Y"), message)?;
+        Ok(())
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SmtpTls {
+    None,
+    /// Upgrades the plain connection, usually port 587
+    StartTls,
+    /// Tls from the start, usually port 465
+    Tls,
+}
+
+impl std::str::FromStr for SmtpTls {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(SmtpTls::None),
+            "starttls" => Ok(SmtpTls::StartTls),
+            "tls" => Ok(SmtpTls::Tls),
+            _ => Err(()),
+        }
+    }
+}
+
+
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// ## Sends the mail straight to an smtp server
+pub struct SmtpTransport {
+    /// host:port
+    address: String,
+    tls: SmtpTls,
+    from: String,
+    transport: lettre::SmtpTransport,
+}
+
+impl std::fmt::Debug for SmtpTransport {
+    // Leaves out the credentials
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SmtpTransport")
+            .field("address", &self.address)
+            .field("tls", &self.tls)
+            .field("from", &self.from)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SmtpTransport {
+    /// Takes the address as host:port
+    pub fn new(address: &str, tls: SmtpTls, credentials: Option<(String, String)>, from: String) -> Result<Self, String> {
+        let (host, port) = address.rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+            .ok_or_else(|| format!("Invalid smtp address \"{}\", expected host:port", address))?;
+
+        let builder = match tls {
+            SmtpTls::None => lettre::SmtpTransport::builder_dangerous(host),
+            #[cfg(feature = "ssl")]
+            SmtpTls::StartTls => lettre::SmtpTransport::starttls_relay(host).map_err(|err| err.to_string())?,
+            #[cfg(feature = "ssl")]
+            SmtpTls::Tls => lettre::SmtpTransport::relay(host).map_err(|err| err.to_string())?,
+            #[cfg(not(feature = "ssl"))]
+            SmtpTls::StartTls | SmtpTls::Tls => {
+                return Err("Smtp over tls needs the server to be built with the ssl feature".to_owned());
+            }
+        };
+        let mut builder = builder
+            .port(port)
+            .timeout(Some(SMTP_TIMEOUT));
+        if let Some((username, password)) = credentials {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        Ok(SmtpTransport {
+            address: address.to_owned(),
+            tls,
+            from,
+            transport: builder.build(),
+        })
+    }
+}
+
+impl MailTransport for SmtpTransport {
+    fn send(&self, mail: &Mail) -> Result<(), MailError> {
+        self.transport.send(&build_message(&self.from, mail)?)?;
+        Ok(())
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    /// Plays the server side of an smtp conversation and returns what the client sent
+    fn fake_smtp_server(listener: TcpListener) -> std::thread::JoinHandle<Vec<String>> {
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut received = Vec::new();
+
+            writer.write_all(b"220 localhost ready\r\n").unwrap();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                let line = line.trim_end_matches("\r\n").to_owned();
+                received.push(line.clone());
+
+                let response: &[u8] = if in_data {
+                    if line != "." { continue; }
+                    in_data = false;
+                    b"250 queued\r\n"
+                } else if line.starts_with("EHLO") {
+                    b"250-localhost\r\n250 AUTH PLAIN\r\n"
+                } else if line.starts_with("AUTH") {
+                    b"235 ok\r\n"
+                } else if line == "DATA" {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line == "QUIT" {
+                    writer.write_all(b"221 bye\r\n").unwrap();
+                    break;
+                } else {
+                    b"250 ok\r\n"
+                };
+                writer.write_all(response).unwrap();
+            }
+            received
+        })
+    }
+
+    #[test]
+    fn smtp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = fake_smtp_server(listener);
+
+        let transport = SmtpTransport::new(
+            &address,
+            SmtpTls::None,
+            Some(("user".to_owned(), "pass".to_owned())),
+            "cookbook@localhost".to_owned(),
+        ).unwrap();
+        transport.send(&Mail {
+            to: "user@example.com".to_owned(),
+            subject: "Test".to_owned(),
+            body: "First line\n.starts with a dot".to_owned(),
+        }).unwrap();
+
+        let received = server.join().unwrap();
+        assert!(received.contains(&"AUTH PLAIN AHVzZXIAcGFzcw==".to_owned()));
+        assert!(received.contains(&"RCPT TO:<user@example.com>".to_owned()));
+        assert!(received.contains(&"Subject: Test".to_owned()));
+        assert!(received.contains(&"..starts with a dot".to_owned()));
+        assert_eq!(received.last().unwrap(), "QUIT");
+    }
+}
@@ -1,10 +1,14 @@
 mod api;
+mod audit;
 mod auth;
 mod db;
+mod logging;
 mod schema;
 mod setup;
 mod macros;
+mod mail;
 mod models;
+mod rate_limit;
 mod unwrap_pretty;
 mod validating;
 
@@ -14,8 +18,6 @@ use lazy_static::lazy_static;
 use std::{env, thread, time::Duration};
 use macros::exit_with_error;
 use unwrap_pretty::UnwrapPretty;
-use actix_extensible_rate_limit::{
-    backend::{memory::InMemoryBackend, SimpleInputFunctionBuilder}, RateLimiter,};
 
 
 // set interval for self cleaning of data, in seconds
@@ -33,6 +35,14 @@ lazy_static! {
         chrono::Duration::hours(5)
         // chrono::Duration::seconds(5)
     };
+    // How much time does the user have to enter the second factor after the password
+    static ref JWT_MFA_DURATION: chrono::Duration = {
+        chrono::Duration::minutes(5)
+    };
+    // For how long is the emailed password reset link valid for
+    static ref PASSWORD_RESET_DURATION: chrono::Duration = {
+        chrono::Duration::minutes(30)
+    };
 }
 
 
@@ -45,6 +55,14 @@ pub async fn hello() -> impl actix_web::Responder {
 #[actix_web::main]
 async fn main() {
     dotenv().ok();
+
+    let log_format = match env::var("CB_LOG_FORMAT") {
+        Ok(val) => val.parse().unwrap_or_else(|err| exit_with_error!("{}", err)),
+        Err(_) => logging::LogFormat::default(),
+    };
+    let log_level = env::var("CB_LOG_LEVEL")
+        .unwrap_or(logging::DEFAULT_LEVEL.to_owned());
+    let log_handle = logging::init(&log_level, log_format).unwrap_pretty("Couldn't set up logging");
     
     // Set default values
     let mut database_path = env::var("CB_DATABASE_PATH")
@@ -92,6 +110,35 @@ async fn main() {
 
                 setup::new_user(&database_path, &username, &pw);
             }
+            "-s:r" => {
+                let username = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No username specified"),
+                };
+
+                let role = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No role specified"),
+                };
+
+                setup::set_role(&database_path, &username, &role);
+            }
+            "-s:ul" => {
+                let username = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No username specified"),
+                };
+
+                setup::unlock_user(&database_path, &username);
+            }
+            "-s:2fa:r" => {
+                let username = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No username specified"),
+                };
+
+                setup::reset_totp(&database_path, &username);
+            }
             "-s:ni" => {
                 let name = match iter.next() {
                     Some(value) => value,
@@ -124,15 +171,258 @@ async fn main() {
 
                 setup::new_jwt_secret(&database_path, Some(jwt_secret));
             }
+            "-s:ts" => {
+                let token_store = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No token store specified"),
+                };
+
+                setup::set_token_store(&database_path, &token_store);
+            }
+            "-s:mail" => {
+                let transport = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No mail transport specified"),
+                };
+
+                // The file transport needs to know where to write
+                let path = if transport == "file" {
+                    match iter.next() {
+                        Some(value) => Some(value),
+                        None => exit_with_error!("No mail file path specified"),
+                    }
+                } else {
+                    None
+                };
+
+                setup::set_mail_transport(&database_path, &transport, path);
+            }
+            "-s:smtp" => {
+                let address = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No smtp server address specified"),
+                };
+
+                let tls = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No smtp tls mode specified"),
+                };
+
+                setup::set_smtp(&database_path, &address, &tls);
+            }
+            "-s:smtp:auth" => {
+                let username = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No smtp username specified"),
+                };
+
+                let password = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No smtp password specified"),
+                };
+
+                setup::set_smtp_auth(&database_path, &username, &password);
+            }
+            "-s:mail:from" => {
+                let address = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No sender address specified"),
+                };
+
+                setup::set_mail_from(&database_path, &address);
+            }
+            "-s:url" => {
+                let url = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No url specified"),
+                };
+
+                setup::set_public_url(&database_path, &url);
+            }
+            "-s:rl:l" => {
+                setup::list_rate_limits(&database_path);
+            }
+            "-s:rl" => {
+                let name = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No rate limiting policy specified"),
+                };
+
+                let max_requests = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No number of requests specified"),
+                };
+
+                let interval_seconds = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No interval specified"),
+                };
+
+                let key_by = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No key specified"),
+                };
+
+                setup::set_rate_limit(&database_path, &name, &max_requests, &interval_seconds, &key_by);
+            }
+            "-s:un" => {
+                let min_length = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No minimal length specified"),
+                };
+
+                let max_length = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No maximal length specified"),
+                };
+
+                let charset = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No charset specified"),
+                };
+
+                setup::set_username_policy(&database_path, &min_length, &max_length, &charset);
+            }
+            "-s:pw" => {
+                let min_length = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No minimal length specified"),
+                };
+
+                let max_length = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No maximal length specified"),
+                };
+
+                let classes = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No character classes specified"),
+                };
+
+                setup::set_password_policy(&database_path, &min_length, &max_length, &classes);
+            }
+            "-s:pw:bl" => {
+                let path = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No path specified"),
+                };
+
+                setup::set_password_blocklist(&database_path, &path);
+            }
+            "-s:argon2" => {
+                let memory = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No memory size specified"),
+                };
+
+                let iterations = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No number of iterations specified"),
+                };
+
+                let parallelism = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No parallelism specified"),
+                };
+
+                setup::set_argon2(&database_path, &memory, &iterations, &parallelism);
+            }
+            "-s:reg" => {
+                let policy = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No registration policy specified"),
+                };
+
+                setup::set_registration(&database_path, &policy);
+            }
+            "-s:ninv" => {
+                let uses = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No number of uses specified"),
+                };
+
+                let days = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No number of days specified"),
+                };
+
+                setup::new_invitation(&database_path, &uses, &days);
+            }
+            "-s:audit" => {
+                let path = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No export path specified"),
+                };
+
+                let days = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No number of days specified"),
+                };
+
+                setup::export_audit_events(&database_path, &path, &days);
+            }
             "-s:j:rand" => {
                 setup::new_jwt_secret(&database_path, None);
             }
+            "-s:ja" => {
+                let algorithm = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No jwt algorithm specified"),
+                };
+
+                // Asymmetric algorithms need the key files
+                let key_paths = if algorithm == "HS256" {
+                    None
+                } else {
+                    let private_key_path = match iter.next() {
+                        Some(value) => value,
+                        None => exit_with_error!("No private key path specified"),
+                    };
+
+                    let public_key_path = match iter.next() {
+                        Some(value) => value,
+                        None => exit_with_error!("No public key path specified"),
+                    };
+
+                    Some((private_key_path, public_key_path))
+                };
+
+                setup::set_jwt_algorithm(&database_path, &algorithm, key_paths);
+            }
+            "-s:jk:l" => {
+                setup::list_jwt_keys(&database_path);
+            }
+            "-s:jk:n" => {
+                setup::new_jwt_key(&database_path);
+            }
+            "-s:jk:a" => {
+                let kid = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No jwt key id specified"),
+                };
+
+                setup::activate_jwt_key(&database_path, &kid);
+            }
+            "-s:jk:r" => {
+                let kid = match iter.next() {
+                    Some(value) => value,
+                    None => exit_with_error!("No jwt key id specified"),
+                };
+
+                setup::retire_jwt_key(&database_path, &kid);
+            }
             "-S" | "--socket" => {
                 match iter.next() {
                     Some(value) => socket = Some(value),
                     None => exit_with_error!("No socket specified"),
                 }
             }
+            "-l" | "--log-level" => {
+                match iter.next() {
+                    Some(value) => log_handle.set_level(&value).unwrap_pretty("Couldn't set the log level"),
+                    None => exit_with_error!("No log level specified"),
+                }
+            }
             "-j" | "--jwt" => {
                 match iter.next() {
                     Some(value) => jwt_secret = Some(value),
@@ -174,27 +464,77 @@ async fn main() {
 
 
     let jwt_conf = {
-        let jwt_secret = match jwt_secret {
-            Some(value) => value,
+        let jwt_conf = match jwt_secret {
+            Some(value) => auth::jwt::new(&value),
             None => {
-                db::key_value::get(&mut conn, "jwt_secret").unwrap_pretty(
-                    "Setting for jwt secret not found. Try setting it in the setup menu (-s flag) or just use \"-j\" for a temporary jwt secret")
+                use chrono::TimeZone;
+
+                let keys = db::jwt_keys::get_usable(&mut conn).unwrap_pretty("Error loading the jwt keys");
+
+                // Asymmetric keys are read from files, the keyring still verifies older tokens
+                let algorithm = db::key_value::get(&mut conn, "jwt_algorithm").unwrap_or("HS256".to_owned());
+                let signing_key = match algorithm.as_str() {
+                    "HS256" => match keys.iter().find(|key| key.status == "active") {
+                        Some(val) => auth::jwt::JwtKey::from_secret(&val.kid, &val.secret),
+                        None => exit_with_error!("No active jwt key found. Try setting one in the setup menu (-s flag) or just use \"-j\" for a temporary jwt secret"),
+                    },
+                    _ => {
+                        let private_key_path = db::key_value::get(&mut conn, "jwt_private_key_path")
+                            .unwrap_pretty("Couldn't get the jwt private key path");
+                        let public_key_path = db::key_value::get(&mut conn, "jwt_public_key_path")
+                            .unwrap_pretty("Couldn't get the jwt public key path");
+                        setup::load_jwt_pem_key(&algorithm, &private_key_path, &public_key_path)
+                    }
+                };
+
+                keys.iter().fold(auth::jwt::JwtConfig::with_signing_key(signing_key), |jwt_conf, key| {
+                    let verifying_key = match key.algorithm.as_str() {
+                        "HS256" => auth::jwt::JwtKey::from_secret(&key.kid, &key.secret),
+                        _ => setup::load_jwt_public_key(&key.algorithm, &key.secret),
+                    };
+                    jwt_conf.verifying_key(match key.verify_until {
+                        Some(val) => verifying_key.verify_until(chrono::Utc.timestamp_opt(val, 0).unwrap()),
+                        None => verifying_key,
+                    })
+                })
             }
         };
 
-        auth::jwt::new(&jwt_secret)
+        let token_storage: Box<dyn auth::token_storage::TokenStorage> = match db::key_value::get(&mut conn, "token_store") {
+            Ok(value) if value == "memory" => Box::new(auth::token_storage::MemoryStorage::new()),
+            Ok(value) if value == "database" => Box::new(auth::token_storage::DatabaseStorage::new(pool.clone())),
+            Ok(value) => exit_with_error!("Unknown token store \"{}\". Try setting it using the \"-s:ts\" flag", value),
+            Err(_) => Box::new(auth::token_storage::DatabaseStorage::new(pool.clone())),
+        };
+
+        jwt_conf.token_storage(token_storage)
     };
 
 
+    let mailer = match mail::load(&mut conn) {
+        Ok(val) => val,
+        Err(err) => exit_with_error!("{}. Try setting it using the \"-s:mail\" or \"-s:smtp\" flag", err),
+    };
+
+    let credential_policy = match validating::CredentialPolicy::load(&mut conn) {
+        Ok(val) => val,
+        Err(err) => exit_with_error!("{}. Try setting it using the \"-s:un\", \"-s:pw\" or \"-s:pw:bl\" flag", err),
+    };
+
     let app_data = web::Data::new(
         models::AppData {
             pool,
             jwt_conf,
+            mailer,
+            credential_policy,
         }
     );
 
-    // Create a rate limiting backend
-    let rate_limiting_backend = InMemoryBackend::builder().build();
+    // Load the rate limiting policies, their counters are shared by all the workers
+    let rate_limits = match rate_limit::RateLimits::load(&mut conn) {
+        Ok(val) => val,
+        Err(err) => exit_with_error!("{}. Try setting it using the \"-s:rl\" flag", err),
+    };
 
     // Set up cleaner thread
     let thread_data = app_data.clone();
@@ -205,27 +545,35 @@ async fn main() {
             thread::sleep(CLEANUP_INTERVAL);
 
             // Do the cleaning
-            thread_data.jwt_conf.clean();
+            if let Err(err) = thread_data.jwt_conf.clean() {
+                tracing::error!(%err, "Couldn't clean the tokens");
+            }
+            match thread_data.pool.get() {
+                Ok(mut conn) => {
+                    if let Err(err) = db::login_attempts::clean(&mut conn) {
+                        tracing::error!(%err, "Couldn't clean the log in attempts");
+                    }
+                    if let Err(err) = db::password_resets::clean(&mut conn) {
+                        tracing::error!(%err, "Couldn't clean the password resets");
+                    }
+                },
+                Err(err) => tracing::error!(%err, "Couldn't get a database connection for cleaning"),
+            }
 
-            println!("{:?}", thread_data.jwt_conf.token_store.tokens);
+            tracing::debug!("Cleaned up the expired data");
         }
     });
 
     // Set up web server
     let server = HttpServer::new(move || {
-        // Create a rate limiting middleware
-        let input = SimpleInputFunctionBuilder::new(Duration::from_secs(60), 5)
-            .real_ip_key()
-            .build();
-        let rate_limiting_middleware = RateLimiter::builder(rate_limiting_backend.clone(), input)
-            .add_headers()
-            .build();
-
         App::new()
-            .wrap(rate_limiting_middleware)
+            .wrap(tracing_actix_web::TracingLogger::default())
             .app_data(app_data.clone())
             .service(hello)
-            .service(web::scope("/api/v1").configure(api::api_v1))
+            .service(web::scope("/api/v1").configure(|cfg| api::api_v1(cfg, &rate_limits)))
+            .service(web::scope("/.well-known")
+                .wrap(rate_limits.limiter("reads"))
+                .configure(api::well_known))
     });
 
     // Bind web server to a socket
@@ -273,13 +621,13 @@ async fn main() {
     };
 
     // Start the web server
-    println!("Strating server at: {}", socket);
+    tracing::info!(%socket, "Starting server");
 
     bound_server.run()
     .await
     .unwrap_or_else(|err| exit_with_error!("Encountered an unexpected error: {}", err));
 
-    println!("Server stopped");
+    tracing::info!("Server stopped");
 }
 
 
@@ -1,13 +1,33 @@
-use crate::{auth, db, schema};
+use crate::{auth, db, mail, schema, validating};
 use serde::{Serialize, Deserialize};
 use diesel::prelude::*;
 
 
-#[derive(Debug, Clone, Queryable, AsChangeset, Insertable, Serialize, Deserialize)]
+#[derive(Clone, Queryable, AsChangeset, Insertable, Serialize, Deserialize)]
 #[diesel(table_name = schema::users)]
 pub struct User {
     pub username: String,
     pub password_hash: String,
+    pub role: String,
+    /// Only used for password resets
+    pub email: Option<String>,
+    /// Disabled accounts can't log in, None if enabled
+    pub disabled_at: Option<i64>,
+    /// Shown to the user when they try to log in
+    pub disabled_reason: Option<String>,
+}
+
+impl std::fmt::Debug for User {
+    // Leaves out the password hash
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("User")
+            .field("username", &self.username)
+            .field("role", &self.role)
+            .field("email", &self.email)
+            .field("disabled_at", &self.disabled_at)
+            .field("disabled_reason", &self.disabled_reason)
+            .finish_non_exhaustive()
+    }
 }
 
 #[derive(Debug, Clone, Queryable, AsChangeset, Insertable, Serialize, Deserialize)]
@@ -16,6 +36,14 @@ pub struct Ingredient {
     pub name: String,
 }
 
+#[derive(Debug, Clone, Queryable, AsChangeset, Insertable, Serialize, Deserialize)]
+#[diesel(table_name = schema::recipes)]
+pub struct Recipe {
+    pub name: String,
+    pub owner: String,
+    pub instructions: String,
+}
+
 #[derive(Debug, Clone, Queryable, AsChangeset, Insertable, Serialize, Deserialize)]
 #[diesel(table_name = schema::ammounts)]
 pub struct AmmountInsertable {
@@ -25,6 +53,153 @@ pub struct AmmountInsertable {
     pub unit: String,
 }
 
+/// ## A single ingredient entry of a recipe, as sent and received by the api
+#[derive(Debug, Clone, Queryable, Serialize, Deserialize)]
+pub struct Ammount {
+    pub kind: String,
+    pub ammount: f32,
+    pub unit: String,
+}
+
+
+/// ## An invitation without its code hash
+#[derive(Debug, Clone, Queryable, Serialize)]
+#[diesel(table_name = schema::invitations)]
+pub struct Invitation {
+    pub id: i32,
+    pub created_by: Option<String>,
+    pub uses_left: i32,
+    pub created_at: i64,
+    pub expiration: i64,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = schema::invitations)]
+pub struct InvitationInsertable {
+    pub code_hash: String,
+    pub created_by: Option<String>,
+    pub uses_left: i32,
+    pub created_at: i64,
+    pub expiration: i64,
+}
+
+/// ## A personal access token without its hash
+#[derive(Debug, Clone, Queryable)]
+#[diesel(table_name = schema::api_tokens)]
+pub struct ApiToken {
+    pub id: i32,
+    pub username: String,
+    pub name: String,
+    /// Space separated list of scopes
+    pub scopes: String,
+    pub created_at: i64,
+    pub expiration: Option<i64>,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = schema::api_tokens)]
+pub struct ApiTokenInsertable {
+    pub token_hash: String,
+    pub username: String,
+    pub name: String,
+    pub scopes: String,
+    pub created_at: i64,
+    pub expiration: Option<i64>,
+}
+
+/// ## Time based one time password settings of a user
+#[derive(Clone, Queryable, Insertable)]
+#[diesel(table_name = schema::totp)]
+pub struct Totp {
+    pub username: String,
+    /// Base32 encoded
+    pub secret: String,
+    pub enabled: bool,
+    pub last_step: i64,
+}
+
+impl std::fmt::Debug for Totp {
+    // Leaves out the secret
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Totp")
+            .field("username", &self.username)
+            .field("enabled", &self.enabled)
+            .field("last_step", &self.last_step)
+            .finish_non_exhaustive()
+    }
+}
+
+/// ## Failed log ins of a username, used to slow down password guessing
+#[derive(Debug, Clone, Queryable, Insertable)]
+#[diesel(table_name = schema::login_attempts)]
+pub struct LoginAttempt {
+    pub username: String,
+    pub failed_count: i32,
+    pub last_failed: i64,
+}
+
+/// ## A named rate limiting policy, key_by is one of: ip or user
+#[derive(Debug, Clone, Queryable, AsChangeset)]
+#[diesel(table_name = schema::rate_limit_policies, primary_key(name))]
+pub struct RateLimitPolicy {
+    pub name: String,
+    pub max_requests: i32,
+    pub interval_seconds: i32,
+    pub key_by: String,
+}
+
+/// ## A jwt signing key, the status is one of: inactive, active or retired
+#[derive(Clone, Queryable, Insertable)]
+#[diesel(table_name = schema::jwt_keys)]
+pub struct JwtKey {
+    pub kid: String,
+    /// The shared secret of HS256 keys, the public key pem of the others
+    pub secret: String,
+    pub status: String,
+    pub created_at: i64,
+    /// Retired keys only verify tokens until this time
+    pub verify_until: Option<i64>,
+    /// Only HS256 keys can be activated, the others are replaced asymmetric keys that only verify
+    pub algorithm: String,
+}
+
+impl std::fmt::Debug for JwtKey {
+    // Leaves out the secret
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JwtKey")
+            .field("kid", &self.kid)
+            .field("algorithm", &self.algorithm)
+            .field("status", &self.status)
+            .field("created_at", &self.created_at)
+            .field("verify_until", &self.verify_until)
+            .finish_non_exhaustive()
+    }
+}
+
+/// ## A recorded security event, the action is one of `audit::Action`
+#[derive(Debug, Clone, Queryable)]
+#[diesel(table_name = schema::audit_events)]
+pub struct AuditEvent {
+    pub id: i32,
+    /// None for anonymous requests and the setup commands
+    pub actor: Option<String>,
+    pub action: String,
+    pub target: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = schema::audit_events)]
+pub struct AuditEventInsertable {
+    pub actor: Option<String>,
+    pub action: String,
+    pub target: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: i64,
+}
 
 #[derive(Debug, Clone, Queryable, AsChangeset, Insertable)]
 #[diesel(table_name = schema::key_value)]
@@ -33,8 +208,26 @@ pub struct KeyValue {
     pub value: String,
 }
 
+#[derive(Debug, Clone, Queryable, Insertable)]
+#[diesel(table_name = schema::tokens)]
+pub struct Token {
+    pub token_hash: String,
+    pub username: String,
+    pub expiration: i64,
+    pub family: Option<String>,
+    pub retired: bool,
+    pub jwt_type: String,
+    pub issuing: i64,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
 #[derive(Debug)]
 pub struct AppData {
     pub pool: db::Pool,
     pub jwt_conf: auth::jwt::JwtConfig,
+    /// None if sending mail is turned off
+    pub mailer: Option<std::sync::Arc<dyn mail::MailTransport>>,
+    /// Rules for new usernames and passwords
+    pub credential_policy: validating::CredentialPolicy,
 }
@@ -0,0 +1,168 @@
+//! ## Named rate limiting policies
+//!
+//! The policies are stored in the database and attached to the api scopes by name,
+//! reads and writes of a scope can use different policies
+
+use crate::{auth, db, models};
+use actix_web::{dev::ServiceRequest, http::Method, web, HttpResponse};
+use actix_extensible_rate_limit::{
+    backend::{memory::InMemoryBackend, SimpleInput, SimpleOutput},
+    RateLimiter,
+};
+use actix_web::rt::time::Instant;
+use std::{collections::HashMap, future::{ready, Ready}, time::Duration};
+
+
+/// Names of the policies used by the api scopes
+pub const POLICY_NAMES: [&str; 3] = ["auth", "reads", "writes"];
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyBy {
+    /// Counts the requests by the client ip
+    Ip,
+    /// Counts the requests by the logged in user, falls back to the ip when not logged in
+    User,
+}
+
+impl std::str::FromStr for KeyBy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ip" => Ok(KeyBy::Ip),
+            "user" => Ok(KeyBy::User),
+            _ => Err(()),
+        }
+    }
+}
+
+
+#[derive(Debug, Clone)]
+pub struct Policy {
+    name: String,
+    max_requests: u64,
+    interval: Duration,
+    key_by: KeyBy,
+}
+
+impl TryFrom<models::RateLimitPolicy> for Policy {
+    type Error = String;
+
+    fn try_from(policy: models::RateLimitPolicy) -> Result<Self, Self::Error> {
+        if policy.max_requests <= 0 || policy.interval_seconds <= 0 {
+            return Err(format!("The \"{}\" rate limiting policy needs a positive number of requests and interval", policy.name));
+        }
+        let key_by = policy.key_by.parse()
+            .map_err(|_| format!("Unknown key \"{}\" of the \"{}\" rate limiting policy, expected one of: ip, user", policy.key_by, policy.name))?;
+
+        Ok(Policy {
+            max_requests: policy.max_requests as u64,
+            interval: Duration::from_secs(policy.interval_seconds as u64),
+            key_by,
+            name: policy.name,
+        })
+    }
+}
+
+impl Policy {
+    fn input(&self, req: &ServiceRequest) -> SimpleInput {
+        let client = match self.key_by {
+            KeyBy::User => user_key(req),
+            KeyBy::Ip => None,
+        }
+        .unwrap_or_else(|| format!("ip:{}", req.connection_info().realip_remote_addr().unwrap_or_default()));
+
+        SimpleInput {
+            interval: self.interval,
+            max_requests: self.max_requests,
+            // Every policy has its own counters
+            key: format!("{}:{}", self.name, client),
+        }
+    }
+}
+
+/// Identifies the user by their access token, the token store isn't checked since the guard does that anyway
+fn user_key(req: &ServiceRequest) -> Option<String> {
+    let token = auth::request_token(req.request(), auth::CookieName::AccessToken)?;
+    if token.starts_with(db::api_tokens::TOKEN_PREFIX) {
+        return Some(format!("token:{}", auth::token_storage::hash_token(&token)));
+    }
+
+    let app_data = req.app_data::<web::Data<models::AppData>>()?;
+    let claims = app_data.jwt_conf.deserialize(app_data.jwt_conf.jwt_from_str(token)).ok()?;
+    Some(format!("user:{}", claims.get_username()))
+}
+
+
+/// ## All the policies along with the shared counters
+#[derive(Clone)]
+pub struct RateLimits {
+    policies: HashMap<String, Policy>,
+    backend: InMemoryBackend,
+}
+
+impl RateLimits {
+    /// Loads the policies from the database, all of `POLICY_NAMES` have to be there
+    pub fn load(conn: &mut db::Conn) -> Result<Self, String> {
+        let policies = db::rate_limit_policies::get_all(conn)
+            .map_err(|err| format!("Couldn't load the rate limiting policies: {}", err))?
+            .into_iter()
+            .map(|policy| Policy::try_from(policy).map(|val| (val.name.clone(), val)))
+            .collect::<Result<HashMap<String, Policy>, String>>()?;
+
+        if let Some(name) = POLICY_NAMES.iter().find(|name| ! policies.contains_key(**name)) {
+            return Err(format!("Missing the \"{}\" rate limiting policy", name));
+        }
+
+        Ok(RateLimits {
+            policies,
+            backend: InMemoryBackend::builder().build(),
+        })
+    }
+
+    /// ## Middleware that limits all requests with a single policy
+    pub fn limiter(&self, name: &str) -> RateLimiter<InMemoryBackend, SimpleOutput, impl Fn(&ServiceRequest) -> Ready<Result<SimpleInput, actix_web::Error>>> {
+        self.split_limiter(name, name)
+    }
+
+    /// ## Middleware that limits the safe methods with the `reads` policy and the rest with the `writes` one
+    /// Panics on unknown names, `load` makes sure `POLICY_NAMES` exist
+    pub fn split_limiter(&self, reads: &str, writes: &str) -> RateLimiter<InMemoryBackend, SimpleOutput, impl Fn(&ServiceRequest) -> Ready<Result<SimpleInput, actix_web::Error>>> {
+        let reads = self.policies[reads].clone();
+        let writes = self.policies[writes].clone();
+
+        let input = move |req: &ServiceRequest| {
+            let policy = match *req.method() {
+                Method::GET | Method::HEAD | Method::OPTIONS => &reads,
+                _ => &writes,
+            };
+            ready(Ok(policy.input(req)))
+        };
+
+        RateLimiter::builder(self.backend.clone(), input)
+            .add_headers()
+            .request_denied_response(denied_response)
+            .build()
+    }
+}
+
+fn denied_response(output: &SimpleOutput) -> HttpResponse {
+    #[derive(serde::Serialize)]
+    struct ResponseData {
+        error: &'static str,
+        /// Seconds until the next request is allowed
+        retry_after: u64,
+    }
+
+    // Round up, so the client doesn't come back a moment too early
+    let wait = output.reset.saturating_duration_since(Instant::now());
+    let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() != 0);
+
+    HttpResponse::TooManyRequests()
+        .append_header((actix_web::http::header::RETRY_AFTER, retry_after))
+        .json(ResponseData {
+            error: "Too many requests",
+            retry_after,
+        })
+}
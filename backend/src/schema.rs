@@ -10,12 +10,58 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    api_tokens (id) {
+        id -> Integer,
+        token_hash -> Text,
+        username -> Text,
+        name -> Text,
+        scopes -> Text,
+        created_at -> BigInt,
+        expiration -> Nullable<BigInt>,
+    }
+}
+
+diesel::table! {
+    audit_events (id) {
+        id -> Integer,
+        actor -> Nullable<Text>,
+        action -> Text,
+        target -> Nullable<Text>,
+        ip -> Nullable<Text>,
+        user_agent -> Nullable<Text>,
+        created_at -> BigInt,
+    }
+}
+
 diesel::table! {
     ingredients (name) {
         name -> Text,
     }
 }
 
+diesel::table! {
+    invitations (id) {
+        id -> Integer,
+        code_hash -> Text,
+        created_by -> Nullable<Text>,
+        uses_left -> Integer,
+        created_at -> BigInt,
+        expiration -> BigInt,
+    }
+}
+
+diesel::table! {
+    jwt_keys (kid) {
+        kid -> Text,
+        secret -> Text,
+        status -> Text,
+        created_at -> BigInt,
+        verify_until -> Nullable<BigInt>,
+        algorithm -> Text,
+    }
+}
+
 diesel::table! {
     key_value (key) {
         key -> Text,
@@ -23,6 +69,31 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    login_attempts (username) {
+        username -> Text,
+        failed_count -> Integer,
+        last_failed -> BigInt,
+    }
+}
+
+diesel::table! {
+    password_resets (token_hash) {
+        token_hash -> Text,
+        username -> Text,
+        expiration -> BigInt,
+    }
+}
+
+diesel::table! {
+    rate_limit_policies (name) {
+        name -> Text,
+        max_requests -> Integer,
+        interval_seconds -> Integer,
+        key_by -> Text,
+    }
+}
+
 diesel::table! {
     recipes (name) {
         name -> Text,
@@ -31,21 +102,72 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    recovery_codes (id) {
+        id -> Integer,
+        username -> Text,
+        code_hash -> Text,
+    }
+}
+
+diesel::table! {
+    tokens (token_hash) {
+        token_hash -> Text,
+        username -> Text,
+        expiration -> BigInt,
+        family -> Nullable<Text>,
+        retired -> Bool,
+        jwt_type -> Text,
+        issuing -> BigInt,
+        user_agent -> Nullable<Text>,
+        ip -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    totp (username) {
+        username -> Text,
+        secret -> Text,
+        enabled -> Bool,
+        last_step -> BigInt,
+    }
+}
+
 diesel::table! {
     users (username) {
         username -> Text,
         password_hash -> Text,
+        role -> Text,
+        email -> Nullable<Text>,
+        disabled_at -> Nullable<BigInt>,
+        disabled_reason -> Nullable<Text>,
     }
 }
 
 diesel::joinable!(ammounts -> ingredients (kind));
+diesel::joinable!(api_tokens -> users (username));
 diesel::joinable!(ammounts -> recipes (recipe));
+diesel::joinable!(invitations -> users (created_by));
+diesel::joinable!(password_resets -> users (username));
 diesel::joinable!(recipes -> users (owner));
+diesel::joinable!(recovery_codes -> users (username));
+diesel::joinable!(tokens -> users (username));
+diesel::joinable!(totp -> users (username));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    api_tokens,
     ammounts,
+    audit_events,
     ingredients,
+    invitations,
+    jwt_keys,
     key_value,
+    login_attempts,
+    password_resets,
+    rate_limit_policies,
     recipes,
+    recovery_codes,
+    tokens,
+    totp,
     users,
 );
@@ -1,4 +1,4 @@
-use crate::{auth, db::{self, Conn}, schema, models, unwrap_pretty::UnwrapPretty, validating};
+use crate::{audit::{self, Action, Event}, auth, db::{self, Conn}, schema, models, unwrap_pretty::UnwrapPretty, validating};
 use crate::macros::{exit_with_error, readln, readpw};
 use std::io::{self, Write};
 use rand::Rng;
@@ -19,15 +19,50 @@ Options:
 -s, --setup                     Enters the setup menu, server won't start after exiting
 -S, --socket                    Sets a temporary socket to bind on
 -j, --jwt                       Sets a temporary jwt secret
+-l, --log-level {level}         Sets the log level or filter, like "debug" or "info,backend=trace"
 -e, --exit                      Exits the server
 -v, --version                   Shows version
 -s:ndb {path} {admin password}  New DataBase. Creates a new database at specified path
 -s:nu {username} {password}     Creates a new user
+-s:r {username} {role}          Sets the role of a user, one of: user, editor, admin
+-s:2fa:r {username}             Resets the two-factor authentication of a user
+-s:ul {username}                Unlocks a user locked out after too many failed log ins
 -s:ni {name}                    Creates a new ingredient
 -s:ri {name}                    Removes an ingredient
 -s:S {socket}                   Sets a new socket
--s:j {secret}                   Sets a new jwt secret
+-s:j {secret}                   Sets a new jwt secret, the old one stays valid until its tokens expire
 -s:j:rand                       Sets a new random jwt secret
+-s:ja {algorithm} [{private key path} {public key path}]
+                                Sets the jwt signing algorithm, one of: HS256, EdDSA, ES256, RS256.
+                                HS256 uses the jwt keys, the others need PEM key files.
+                                The replaced key stays valid until its tokens expire
+-s:jk:l                         Lists the jwt keys
+-s:jk:n                         Adds a new random jwt key without activating it
+-s:jk:a {kid}                   Activates a jwt key, the previous one gets retired
+-s:jk:r {kid}                   Retires a jwt key, tokens signed with it stop being valid
+-s:ts {memory|database}         Sets where the valid tokens are stored
+-s:mail {none|stdout|file} [{path}]
+                                Sets where the mail is sent, file needs the path of an mbox file to append to,
+                                stdout is only meant for testing, it prints the password reset links
+-s:smtp {host:port} {none|starttls|tls}
+                                Sends the mail through an smtp server, tls needs the ssl feature
+-s:smtp:auth {username} {password}
+                                Sets the smtp server credentials
+-s:mail:from {address}          Sets the sender address of the mail
+-s:url {url}                    Sets the public url of the site, used for links in the mail
+-s:rl:l                         Lists the rate limiting policies
+-s:rl {name} {max requests} {interval seconds} {ip|user}
+                                Sets a rate limiting policy, one of: auth, reads, writes.
+                                Requests are counted by the client ip or by the logged in user
+-s:un {min} {max} {any|ascii}   Sets the length limits of new usernames, ascii (the default) forbids the other characters
+-s:pw {min} {max} {classes}     Sets the length limits of new passwords and the required character classes,
+                                a comma separated list of: lower, upper, digit, special, or none
+-s:pw:bl {path|none}            Sets a file of breached or common passwords, one per line, that can't be used
+-s:argon2 {memory KiB} {iterations} {parallelism}
+                                Sets the password hashing parameters, the older hashes get replaced on log in
+-s:reg {policy}                 Sets who can register through the api: open, invite_only or closed
+-s:ninv {uses} {days}           Creates a new invitation code valid for the specified number of days, up to 365
+-s:audit {path|-} {days|all}    Exports the audit events of the last days, up to 3650, as json lines, - writes to stdout
 
 Examples:
 - "#, NAME, r#"
@@ -47,6 +82,9 @@ What would you like to do?
 4) Recover password
 5) Add a new ingredient
 6) Remove an ingredient
+7) Change the role of a user
+8) Reset two-factor authentication of a user
+9) Unlock a user
 
 > "#;
 
@@ -86,13 +124,13 @@ pub fn setup(db_path: &str) -> ! {
             );
         },
         "3" => { // Create new users
+            let policy = credential_policy(db_path);
+
             let username = readln!("Username: ");
-            if ! validating::is_valid_username(&username) {
-                exit_with_error!("Invalid username. Check help page for more informations")}
+            exit_on_violations(policy.username.check(&username));
 
             let pw = readpw!("Password: ");
-            if ! validating::is_valid_password(&pw) {
-                exit_with_error!("Invalid password. Check help page for more informations")}
+            exit_on_violations(policy.password.check(&pw));
 
             let confirmation_pw = readpw!("Confirm password: ");
             if confirmation_pw != pw {
@@ -102,8 +140,10 @@ pub fn setup(db_path: &str) -> ! {
             new_user(db_path, username.trim(), pw.trim());
         },
         "4" => { // Recover password
-            let pool: db::Pool = db::establish_connection(format!("sqlite://{}", db_path));
+            let pool: db::Pool = validate_db(db_path);
             let mut conn: Conn = pool.get().unwrap();
+            let policy = validating::CredentialPolicy::load(&mut conn)
+                .unwrap_or_else(|err| exit_with_error!("{}", err));
 
             let username = readln!("Username: ");
             let result: Option<models::User> = schema::users::dsl::users
@@ -115,8 +155,7 @@ pub fn setup(db_path: &str) -> ! {
             if result.is_none() { exit_with_error!("User not found"); }
 
             let pw = readpw!("Enter a new password: ");
-            if ! validating::is_valid_password(&pw) {
-                exit_with_error!("Invalid password. Check help page for more informations")}
+            exit_on_violations(policy.password.check(&pw));
             let confirmation_pw = readpw!("Confirm new password: ");
             if confirmation_pw != pw {
                 exit_with_error!("Mismatched passwords")
@@ -124,10 +163,11 @@ pub fn setup(db_path: &str) -> ! {
 
             let pw_hash = auth::hash_password(&pw);
             diesel::update(schema::users::dsl::users
-                .filter(schema::users::dsl::username.eq(username)))
+                .filter(schema::users::dsl::username.eq(&username)))
                 .set(schema::users::dsl::password_hash.eq(pw_hash))
                 .execute(&mut conn)
                 .unwrap_pretty("Error setting password");
+            Event::cli(Action::PasswordChanged).target(&username).record(&mut conn);
 
             println!("Successfully changed the password");
         },
@@ -137,11 +177,23 @@ pub fn setup(db_path: &str) -> ! {
             new_ingredient(db_path, &name);
         },
         "6" => { // Remove an ingredient
-            println!("Before you proceed, keep in mind that removing an ingredient that is already being used in a recipe might have unexpected consequences!");
             let name = readln!("Name of the ingredient to remove: ");
             if name.is_empty() { exit_with_error!("Ingredient name cannot be empty") }
             remove_ingredient(db_path, &name);
         },
+        "7" => { // Change the role of a user
+            let username = readln!("Username: ");
+            let role = readln!("New role (user, editor, admin): ");
+            set_role(db_path, &username, &role);
+        },
+        "8" => { // Reset two-factor authentication of a user
+            let username = readln!("Username: ");
+            reset_totp(db_path, &username);
+        },
+        "9" => { // Unlock a user
+            let username = readln!("Username: ");
+            unlock_user(db_path, &username);
+        },
         _ => exit_with_error!("Invalid option")
     }
 
@@ -149,6 +201,25 @@ pub fn setup(db_path: &str) -> ! {
 }
 
 pub fn validate_db(db_path: &str) -> db::Pool {
+    let pool = open_db(db_path);
+    let mut conn: Conn = pool.get().unwrap();
+
+    use schema::users::dsl::*;
+
+    let result = diesel::select(diesel::dsl::exists(users.filter(role.eq(auth::Role::Admin.to_string()))))
+        .get_result::<bool>(&mut conn);
+
+    match result {
+        Ok(true) => {},
+        Ok(false) => exit_with_error!("No admin account found, try giving one of the users the admin role using the -s:r flag"),
+        Err(err) => exit_with_error!("Unexpected error during database validation: {}", err),
+    }
+
+    pool
+}
+
+/// Like validate_db but without requiring an admin, so one can be set when there is none
+fn open_db(db_path: &str) -> db::Pool {
     // Validate database_path
     if std::fs::metadata(db_path).is_err() {
         exit_with_error!("Database file not found at specified path \"{}\", try creating it using the -s or --setup flag", db_path);
@@ -157,9 +228,8 @@ pub fn validate_db(db_path: &str) -> db::Pool {
     let pool: db::Pool = db::establish_connection(format!("sqlite://{}", db_path));
     let mut conn: Conn = pool.get().unwrap();
 
-    use schema::users::dsl::*;
-
-    let result = diesel::select(diesel::dsl::exists(users.filter(username.eq("admin"))))
+    let result = diesel::select(diesel::dsl::sql::<diesel::sql_types::Bool>(
+        "EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'users')"))
         .get_result::<bool>(&mut conn);
 
     // Validate database
@@ -168,6 +238,17 @@ pub fn validate_db(db_path: &str) -> db::Pool {
         _ => {}
     }
 
+    // Upgrade databases created by older versions
+    if let Err(err) = db::run_migrations(&mut conn) {
+        exit_with_error!("Failed to run the database migrations: {}", err);
+    }
+
+    // New passwords get hashed with the configured parameters
+    match auth::load_argon2_params(&mut conn) {
+        Ok(val) => auth::set_argon2_params(val),
+        Err(err) => exit_with_error!("{}. Try setting them using the \"-s:argon2\" flag", err),
+    }
+
     pool
 }
 
@@ -183,19 +264,22 @@ pub fn new_db_file(db_path: &str, admin_pw: &str) {
     let pool: db::Pool = db::establish_connection(format!("sqlite://{}", db_path));
     let mut conn: Conn = pool.get().unwrap();
 
-    for query in db::SQL_DECLARATION.split(';') {
-        let trimmed_query = query.trim();
-        if !trimmed_query.is_empty() {
-            if let Err(err) = diesel::sql_query(trimmed_query).execute(&mut conn) {
-                exit_with_error!("Failed to execute the initial sql query: {}", err)
-            }
-        }
+    if let Err(err) = db::run_migrations(&mut conn) {
+        exit_with_error!("Failed to run the database migrations: {}", err)
+    }
+
+    if let Err(err) = diesel::sql_query(db::SQL_DECLARATION).execute(&mut conn) {
+        exit_with_error!("Failed to execute the initial sql query: {}", err)
     }
 
     let result = diesel::insert_into(schema::users::dsl::users)
     .values(models::User {
         username: "admin".to_owned(),
         password_hash: auth::hash_password(admin_pw),
+        role: auth::Role::Admin.to_string(),
+        email: None,
+        disabled_at: None,
+        disabled_reason: None,
     })
     .execute(&mut conn);
 
@@ -205,47 +289,218 @@ pub fn new_db_file(db_path: &str, admin_pw: &str) {
     }
 }
 
+fn random_jwt_secret() -> String {
+    let mut rng = rand::thread_rng();
+    (0..32)
+        .map(|_| rng.sample(rand::distributions::Alphanumeric) as char)
+        .collect()
+}
+
+/// Adds a new jwt key and activates it right away, tokens signed with the previous key stay valid until they expire
 pub fn new_jwt_secret(db_path: &str, jwt_secret: Option<String>) {
-    let jwt_secret = match jwt_secret {
-        Some(val) => val,
-        None => {
-            let mut rng = rand::thread_rng();
-            (0..32)
-                .map(|_| rng.sample(rand::distributions::Alphanumeric) as char)
-                .collect()
+    let jwt_secret = jwt_secret.unwrap_or_else(random_jwt_secret);
+
+    let pool = validate_db(db_path);
+    let mut conn = pool.get().unwrap();
+    let kid = db::jwt_keys::add(&mut conn, &jwt_secret).unwrap_pretty(
+        "Error adding the jwt key");
+    db::jwt_keys::activate(&mut conn, &kid, *crate::JWT_REFRESH_DURATION).unwrap_pretty(
+        "Error activating the jwt key");
+    Event::cli(Action::JwtKeyCreated).target(&kid).record(&mut conn);
+    Event::cli(Action::JwtKeyActivated).target(&kid).record(&mut conn);
+
+    println!("Successfully set new jwt secret with kid \"{}\"", kid);
+}
+
+/// Loads an asymmetric jwt key pair from PEM files
+pub fn load_jwt_pem_key(algorithm: &str, private_key_path: &str, public_key_path: &str) -> auth::jwt::JwtKey {
+    let algorithm: jsonwebtoken::Algorithm = match algorithm.parse() {
+        Ok(val @ (jsonwebtoken::Algorithm::EdDSA | jsonwebtoken::Algorithm::ES256 | jsonwebtoken::Algorithm::RS256)) => val,
+        _ => exit_with_error!("Invalid jwt algorithm \"{}\", expected one of: HS256, EdDSA, ES256, RS256", algorithm),
+    };
+    let private_pem = match std::fs::read(private_key_path) {
+        Ok(val) => val,
+        Err(err) => exit_with_error!("Couldn't read the jwt private key at \"{}\": {}", private_key_path, err),
+    };
+    let public_pem = match std::fs::read(public_key_path) {
+        Ok(val) => val,
+        Err(err) => exit_with_error!("Couldn't read the jwt public key at \"{}\": {}", public_key_path, err),
+    };
+
+    match auth::jwt::JwtKey::from_pem(algorithm, &private_pem, &public_pem) {
+        Ok(val) => val,
+        Err(err) => exit_with_error!("Invalid {:?} key pair: {}", algorithm, err),
+    }
+}
+
+/// Loads the public key of a replaced asymmetric key from the keyring
+pub fn load_jwt_public_key(algorithm: &str, public_pem: &str) -> auth::jwt::JwtKey {
+    let algorithm: jsonwebtoken::Algorithm = match algorithm.parse() {
+        Ok(val) => val,
+        Err(_) => exit_with_error!("Invalid jwt algorithm \"{}\" in the keyring", algorithm),
+    };
+
+    match auth::jwt::JwtKey::from_public_pem(algorithm, public_pem.as_bytes()) {
+        Ok(val) => val,
+        Err(err) => exit_with_error!("Invalid {:?} public key in the keyring: {}", algorithm, err),
+    }
+}
+
+/// Keeps the public key of the current asymmetric key in the keyring, so the tokens signed with it stay valid until they expire.
+/// Nothing is kept if the current algorithm is HS256 or the key stays the same
+fn retire_jwt_pem_key(conn: &mut Conn, new_kid: Option<&str>) {
+    let algorithm = match db::key_value::get(conn, "jwt_algorithm") {
+        Ok(val) if val != "HS256" => val,
+        _ => return,
+    };
+
+    let public_key_path = db::key_value::get(conn, "jwt_public_key_path").unwrap_pretty(
+        "Couldn't get the jwt public key path");
+    let public_pem = match std::fs::read_to_string(&public_key_path) {
+        Ok(val) => val,
+        Err(err) => {
+            eprintln!("Couldn't read the previous jwt public key at \"{}\", tokens signed with it stop being valid: {}", public_key_path, err);
+            return;
         }
     };
+    let kid = load_jwt_public_key(&algorithm, &public_pem).get_kid().to_owned();
+    if new_kid == Some(kid.as_str()) {
+        return;
+    }
+
+    db::jwt_keys::add_retired_public(conn, &kid, &algorithm, &public_pem, *crate::JWT_REFRESH_DURATION).unwrap_pretty(
+        "Error keeping the previous jwt key");
+    Event::cli(Action::JwtKeyRetired).target(&kid).record(conn);
+    println!("The previous jwt key \"{}\" keeps verifying tokens until they expire", kid);
+}
 
+/// Asymmetric algorithms need key files, HS256 goes back to using the jwt keys
+pub fn set_jwt_algorithm(db_path: &str, algorithm: &str, key_paths: Option<(String, String)>) {
     let pool = validate_db(db_path);
     let mut conn = pool.get().unwrap();
-    db::key_value::set(&mut conn, "jwt_secret", &jwt_secret).unwrap_pretty(
+
+    match key_paths {
+        None if algorithm == "HS256" => retire_jwt_pem_key(&mut conn, None),
+        None => exit_with_error!("The {} algorithm needs the private and public key paths", algorithm),
+        Some((private_key_path, public_key_path)) => {
+            // Check the keys now rather than on the next start
+            let key = load_jwt_pem_key(algorithm, &private_key_path, &public_key_path);
+            println!("Loaded the jwt key with kid \"{}\"", key.get_kid());
+            retire_jwt_pem_key(&mut conn, Some(key.get_kid()));
+
+            db::key_value::set(&mut conn, "jwt_private_key_path", &private_key_path).unwrap_pretty(
+                "Error setting the key value pair");
+            db::key_value::set(&mut conn, "jwt_public_key_path", &public_key_path).unwrap_pretty(
+                "Error setting the key value pair");
+        }
+    }
+
+    db::key_value::set(&mut conn, "jwt_algorithm", algorithm).unwrap_pretty(
         "Error setting the key value pair");
+    Event::cli(Action::SettingChanged).target("jwt_algorithm").record(&mut conn);
 
-    println!("Successfully set new jwt secret");
+    println!("Successfuly set the jwt algorithm to \"{}\"", algorithm);
 }
 
-pub fn new_user(db_path: &str, username: &str, password: &str) {
-    if ! validating::is_valid_username(username) {
-        exit_with_error!("Invalid username. Check help page for more informations")}
-    if ! validating::is_valid_password(password) {
-        exit_with_error!("Invalid password. Check help page for more informations")}
+/// Adds a new random jwt key without activating it
+pub fn new_jwt_key(db_path: &str) {
+    let pool = validate_db(db_path);
+    let mut conn = pool.get().unwrap();
+    let kid = db::jwt_keys::add(&mut conn, &random_jwt_secret()).unwrap_pretty(
+        "Error adding the jwt key");
+    Event::cli(Action::JwtKeyCreated).target(&kid).record(&mut conn);
 
-    let pool: db::Pool = validate_db(db_path);
-    let mut conn: Conn = pool.get().unwrap();
+    println!("Successfully added a new jwt key with kid \"{}\", activate it using the -s:jk:a flag", kid);
+}
 
-    let result = diesel::insert_into(schema::users::dsl::users)
-    .values(models::User {
-        username: username.to_owned(),
-        password_hash: auth::hash_password(password),
-    })
-    .execute(&mut conn);
+pub fn list_jwt_keys(db_path: &str) {
+    use chrono::TimeZone;
 
-    match result {
-        Ok(_) => println!("A new user \"{}\" has been created", username),
-        Err(diesel::result::Error::DatabaseError(diesel::result::DatabaseErrorKind::UniqueViolation, _)) => {
-            exit_with_error!("The user \"{}\" already exists", username);
+    let pool = validate_db(db_path);
+    let mut conn = pool.get().unwrap();
+    let keys = db::jwt_keys::get_all(&mut conn).unwrap_pretty(
+        "Error loading the jwt keys");
+
+    for key in keys {
+        let created_at = chrono::Utc.timestamp_opt(key.created_at, 0).unwrap();
+        match key.verify_until {
+            Some(val) => println!("{}  {:<5}  {:<8}  created {}  verifies until {}",
+                key.kid, key.algorithm, key.status, created_at, chrono::Utc.timestamp_opt(val, 0).unwrap()),
+            None => println!("{}  {:<5}  {:<8}  created {}", key.kid, key.algorithm, key.status, created_at),
         }
-        Err(err) => exit_with_error!("Unexpected error: {}", err)
+    }
+}
+
+/// The previously active key keeps verifying tokens until they expire
+pub fn activate_jwt_key(db_path: &str, kid: &str) {
+    let pool = validate_db(db_path);
+    let mut conn = pool.get().unwrap();
+
+    match db::jwt_keys::activate(&mut conn, kid, *crate::JWT_REFRESH_DURATION) {
+        Ok(0) => exit_with_error!("Jwt key not found, only HS256 keys can be activated"),
+        Ok(_) => {
+            Event::cli(Action::JwtKeyActivated).target(kid).record(&mut conn);
+            println!("Successfuly activated the jwt key \"{}\", restart the server to start using it", kid);
+        },
+        Err(err) => exit_with_error!("Couldn't activate the jwt key: {}", err),
+    }
+}
+
+/// Tokens signed with the key stop being valid right away
+pub fn retire_jwt_key(db_path: &str, kid: &str) {
+    let pool = validate_db(db_path);
+    let mut conn = pool.get().unwrap();
+
+    match db::jwt_keys::get(&mut conn, kid) {
+        Ok(Some(key)) if key.status == "active" => exit_with_error!("Can't retire the active jwt key, activate a different one instead"),
+        Ok(Some(_)) => {},
+        Ok(None) => exit_with_error!("Jwt key not found"),
+        Err(err) => exit_with_error!("Couldn't retire the jwt key: {}", err),
+    }
+
+    match db::jwt_keys::retire(&mut conn, kid) {
+        Ok(_) => {
+            Event::cli(Action::JwtKeyRetired).target(kid).record(&mut conn);
+            println!("Successfuly retired the jwt key \"{}\"", kid);
+        },
+        Err(err) => exit_with_error!("Couldn't retire the jwt key: {}", err),
+    }
+}
+
+/// Loads the rules for new usernames and passwords
+fn credential_policy(db_path: &str) -> validating::CredentialPolicy {
+    let pool = validate_db(db_path);
+    let mut conn = pool.get().unwrap();
+    match validating::CredentialPolicy::load(&mut conn) {
+        Ok(val) => val,
+        Err(err) => exit_with_error!("{}", err),
+    }
+}
+
+/// Exits listing the broken rules, if there are any
+fn exit_on_violations(violations: Vec<validating::Violation>) {
+    if ! violations.is_empty() {
+        let violations: Vec<String> = violations.iter().map(|val| val.to_string()).collect();
+        exit_with_error!("Broken rules: {}. Check help page for more informations", violations.join(", "));
+    }
+}
+
+pub fn new_user(db_path: &str, username: &str, password: &str) {
+    let pool: db::Pool = validate_db(db_path);
+    let mut conn: Conn = pool.get().unwrap();
+    let policy = match validating::CredentialPolicy::load(&mut conn) {
+        Ok(val) => val,
+        Err(err) => exit_with_error!("{}", err),
+    };
+
+    match db::users::create(&mut conn, &policy, username, password, auth::Role::User, None) {
+        Ok(_) => {
+            Event::cli(Action::UserCreated).target(username).record(&mut conn);
+            println!("A new user \"{}\" has been created", username);
+        },
+        Err(db::users::CreateError::Invalid(violations)) => exit_on_violations(violations),
+        Err(db::users::CreateError::Exists) => exit_with_error!("The user \"{}\" already exists", username),
+        Err(db::users::CreateError::Database(err)) => exit_with_error!("Unexpected error: {}", err),
     }
 }
 
@@ -263,7 +518,10 @@ pub fn new_ingredient(db_path: &str, name: &str) {
     .execute(&mut conn);
 
     match result {
-        Ok(_) => println!("A new ingredient \"{}\" has been created", name),
+        Ok(_) => {
+            Event::cli(Action::IngredientCreated).target(name).record(&mut conn);
+            println!("A new ingredient \"{}\" has been created", name);
+        },
         Err(diesel::result::Error::DatabaseError(diesel::result::DatabaseErrorKind::UniqueViolation, _)) => {
             exit_with_error!("The ingredient \"{}\" already exists", name);
         }
@@ -275,6 +533,10 @@ pub fn remove_ingredient(db_path: &str, name: &str) {
     let pool: db::Pool = validate_db(db_path);
     let mut conn: Conn = pool.get().unwrap();
 
+    if db::ingredients::is_used(&mut conn, name).unwrap_pretty("Error loading data") {
+        exit_with_error!("The ingredient \"{}\" is still used in a recipe", name);
+    }
+
     let result = diesel::delete(
             schema::ingredients::dsl::ingredients
             .filter(schema::ingredients::dsl::name.eq(name))
@@ -283,7 +545,10 @@ pub fn remove_ingredient(db_path: &str, name: &str) {
 
     match result {
         Ok(0) => exit_with_error!("No ingredient with this name found"),
-        Ok(_) => println!("Successfuly removed the ingredient \"{}\"", name),
+        Ok(_) => {
+            Event::cli(Action::IngredientDeleted).target(name).record(&mut conn);
+            println!("Successfuly removed the ingredient \"{}\"", name);
+        },
         Err(err) => exit_with_error!("Couldn't remove the ingredient: {}", err),
     }
 }
@@ -297,7 +562,337 @@ pub fn set_socket(db_path: &str, socket: &str) {
     let mut conn = pool.get().unwrap();
     db::key_value::set(&mut conn, "socket", socket).unwrap_pretty(
         "Error setting the key value pair");
+    Event::cli(Action::SettingChanged).target("socket").record(&mut conn);
 
     println!("Successfuly set the socket to \"{}\"", socket);
 }
 
+pub fn set_token_store(db_path: &str, token_store: &str) {
+    if ! ["memory", "database"].contains(&token_store) {
+        exit_with_error!("Invalid token store, expected \"memory\" or \"database\"");
+    }
+
+    let pool = validate_db(db_path);
+    let mut conn = pool.get().unwrap();
+    db::key_value::set(&mut conn, "token_store", token_store).unwrap_pretty(
+        "Error setting the key value pair");
+    Event::cli(Action::SettingChanged).target("token_store").record(&mut conn);
+
+    println!("Successfuly set the token store to \"{}\"", token_store);
+}
+
+/// Everything except smtp, which is set by `set_smtp`
+pub fn set_mail_transport(db_path: &str, transport: &str, path: Option<String>) {
+    if ! ["none", "stdout", "file"].contains(&transport) {
+        exit_with_error!("Invalid mail transport, expected one of: none, stdout, file");
+    }
+
+    let pool = validate_db(db_path);
+    let mut conn = pool.get().unwrap();
+    if let Some(path) = path {
+        db::key_value::set(&mut conn, "mail_file_path", &path).unwrap_pretty(
+            "Error setting the key value pair");
+    }
+    db::key_value::set(&mut conn, "mail_transport", transport).unwrap_pretty(
+        "Error setting the key value pair");
+    Event::cli(Action::SettingChanged).target("mail_transport").record(&mut conn);
+
+    println!("Successfuly set the mail transport to \"{}\"", transport);
+}
+
+pub fn set_smtp(db_path: &str, address: &str, tls: &str) {
+    if tls.parse::<crate::mail::SmtpTls>().is_err() {
+        exit_with_error!("Invalid smtp tls mode, expected one of: none, starttls, tls");
+    }
+    if tls != "none" && ! cfg!(feature = "ssl") {
+        exit_with_error!("Smtp over tls needs the server to be built with the ssl feature");
+    }
+    if ! validating::is_valid_socket(address) {
+        exit_with_error!("Invalid smtp server address");
+    }
+
+    let pool = validate_db(db_path);
+    let mut conn = pool.get().unwrap();
+    for (key, value) in [("smtp_address", address), ("smtp_tls", tls), ("mail_transport", "smtp")] {
+        db::key_value::set(&mut conn, key, value).unwrap_pretty(
+            "Error setting the key value pair");
+        Event::cli(Action::SettingChanged).target(key).record(&mut conn);
+    }
+
+    println!("Successfuly set the smtp server to \"{}\"", address);
+}
+
+pub fn set_smtp_auth(db_path: &str, username: &str, password: &str) {
+    let pool = validate_db(db_path);
+    let mut conn = pool.get().unwrap();
+    for (key, value) in [("smtp_username", username), ("smtp_password", password)] {
+        db::key_value::set(&mut conn, key, value).unwrap_pretty(
+            "Error setting the key value pair");
+        Event::cli(Action::SettingChanged).target(key).record(&mut conn);
+    }
+
+    println!("Successfuly set the smtp credentials");
+}
+
+pub fn set_mail_from(db_path: &str, address: &str) {
+    if ! validating::is_valid_email(address) {
+        exit_with_error!("Invalid email address");
+    }
+
+    let pool = validate_db(db_path);
+    let mut conn = pool.get().unwrap();
+    db::key_value::set(&mut conn, "mail_from", address).unwrap_pretty(
+        "Error setting the key value pair");
+    Event::cli(Action::SettingChanged).target("mail_from").record(&mut conn);
+
+    println!("Successfuly set the sender address to \"{}\"", address);
+}
+
+pub fn set_public_url(db_path: &str, url: &str) {
+    if ! (url.starts_with("http://") || url.starts_with("https://")) {
+        exit_with_error!("Invalid url, it has to start with http:// or https://");
+    }
+
+    let pool = validate_db(db_path);
+    let mut conn = pool.get().unwrap();
+    db::key_value::set(&mut conn, "public_url", url).unwrap_pretty(
+        "Error setting the key value pair");
+    Event::cli(Action::SettingChanged).target("public_url").record(&mut conn);
+
+    println!("Successfuly set the public url to \"{}\"", url);
+}
+
+pub fn set_role(db_path: &str, username: &str, role: &str) {
+    let role: auth::Role = match role.parse() {
+        Ok(val) => val,
+        Err(_) => exit_with_error!("Invalid role, expected one of: user, editor, admin"),
+    };
+
+    let pool: db::Pool = open_db(db_path);
+    let mut conn: Conn = pool.get().unwrap();
+
+    let result = diesel::update(schema::users::dsl::users.find(username))
+        .set(schema::users::dsl::role.eq(role.to_string()))
+        .execute(&mut conn);
+
+    match result {
+        Ok(0) => exit_with_error!("User not found"),
+        Ok(_) => {
+            Event::cli(Action::RoleChanged).target(username).record(&mut conn);
+            println!("Successfuly set the role of \"{}\" to \"{}\"", username, role);
+        },
+        Err(err) => exit_with_error!("Couldn't set the role: {}", err),
+    }
+}
+
+/// For users that lost both their authenticator and the recovery codes
+pub fn reset_totp(db_path: &str, username: &str) {
+    let pool: db::Pool = validate_db(db_path);
+    let mut conn: Conn = pool.get().unwrap();
+
+    match db::totp::remove(&mut conn, username) {
+        Ok(0) => exit_with_error!("User not found or two-factor authentication not enabled"),
+        Ok(_) => {
+            Event::cli(Action::TwoFactorReset).target(username).record(&mut conn);
+            println!("Successfuly reset the two-factor authentication of \"{}\"", username);
+        },
+        Err(err) => exit_with_error!("Couldn't reset the two-factor authentication: {}", err),
+    }
+}
+
+pub fn unlock_user(db_path: &str, username: &str) {
+    let pool: db::Pool = validate_db(db_path);
+    let mut conn: Conn = pool.get().unwrap();
+
+    match db::login_attempts::clear(&mut conn, username) {
+        Ok(0) => exit_with_error!("No failed log ins found for \"{}\"", username),
+        Ok(_) => {
+            Event::cli(Action::UserUnlocked).target(username).record(&mut conn);
+            println!("Successfuly unlocked \"{}\"", username);
+        },
+        Err(err) => exit_with_error!("Couldn't unlock the user: {}", err),
+    }
+}
+
+pub fn list_rate_limits(db_path: &str) {
+    let pool = validate_db(db_path);
+    let mut conn = pool.get().unwrap();
+    let policies = db::rate_limit_policies::get_all(&mut conn).unwrap_pretty(
+        "Error loading the rate limiting policies");
+
+    for policy in policies {
+        println!("{:<8}  {} requests per {}s  by {}",
+            policy.name, policy.max_requests, policy.interval_seconds, policy.key_by);
+    }
+}
+
+pub fn set_rate_limit(db_path: &str, name: &str, max_requests: &str, interval_seconds: &str, key_by: &str) {
+    let policy = models::RateLimitPolicy {
+        name: name.to_owned(),
+        max_requests: max_requests.parse().unwrap_pretty("Invalid number of requests"),
+        interval_seconds: interval_seconds.parse().unwrap_pretty("Invalid interval"),
+        key_by: key_by.to_owned(),
+    };
+    // Reuse the validation done when starting the server
+    if let Err(err) = crate::rate_limit::Policy::try_from(policy.clone()) {
+        exit_with_error!("{}", err);
+    }
+
+    let pool = validate_db(db_path);
+    let mut conn = pool.get().unwrap();
+
+    match db::rate_limit_policies::update(&mut conn, &policy) {
+        Ok(0) => exit_with_error!("Rate limiting policy not found, expected one of: {}", crate::rate_limit::POLICY_NAMES.join(", ")),
+        Ok(_) => {
+            Event::cli(Action::SettingChanged).target(format!("rate_limit.{}", name)).record(&mut conn);
+            println!("Successfuly set the \"{}\" rate limiting policy, restart the server to start using it", name);
+        },
+        Err(err) => exit_with_error!("Couldn't set the rate limiting policy: {}", err),
+    }
+}
+
+pub fn set_argon2(db_path: &str, memory: &str, iterations: &str, parallelism: &str) {
+    let memory: u32 = memory.parse().unwrap_pretty("Invalid memory size");
+    let iterations: u32 = iterations.parse().unwrap_pretty("Invalid number of iterations");
+    let parallelism: u32 = parallelism.parse().unwrap_pretty("Invalid parallelism");
+    if let Err(err) = auth::argon2_params(memory, iterations, parallelism) {
+        exit_with_error!("{}", err);
+    }
+
+    let pool = validate_db(db_path);
+    let mut conn = pool.get().unwrap();
+    db::key_value::set(&mut conn, "argon2_memory", &memory.to_string())
+        .and_then(|_| db::key_value::set(&mut conn, "argon2_iterations", &iterations.to_string()))
+        .and_then(|_| db::key_value::set(&mut conn, "argon2_parallelism", &parallelism.to_string()))
+        .unwrap_pretty("Error setting the key value pair");
+    Event::cli(Action::SettingChanged).target("argon2").record(&mut conn);
+
+    println!("Successfuly set the password hashing parameters, restart the server to start using them");
+}
+
+pub fn set_username_policy(db_path: &str, min_length: &str, max_length: &str, charset: &str) {
+    if let Err(err) = validating::UsernamePolicy::new(min_length, max_length, charset) {
+        exit_with_error!("{}", err);
+    }
+
+    let pool = validate_db(db_path);
+    let mut conn = pool.get().unwrap();
+    db::key_value::set(&mut conn, "username_policy", &format!("{} {} {}", min_length, max_length, charset))
+        .unwrap_pretty("Error setting the key value pair");
+    Event::cli(Action::SettingChanged).target("username_policy").record(&mut conn);
+
+    println!("Successfuly set the username policy, restart the server to start using it");
+}
+
+pub fn set_password_policy(db_path: &str, min_length: &str, max_length: &str, classes: &str) {
+    if let Err(err) = validating::PasswordPolicy::new(min_length, max_length, classes) {
+        exit_with_error!("{}", err);
+    }
+
+    let pool = validate_db(db_path);
+    let mut conn = pool.get().unwrap();
+    db::key_value::set(&mut conn, "password_policy", &format!("{} {} {}", min_length, max_length, classes))
+        .unwrap_pretty("Error setting the key value pair");
+    Event::cli(Action::SettingChanged).target("password_policy").record(&mut conn);
+
+    println!("Successfuly set the password policy, restart the server to start using it");
+}
+
+pub fn set_password_blocklist(db_path: &str, path: &str) {
+    let pool = validate_db(db_path);
+    let mut conn = pool.get().unwrap();
+
+    if path == "none" {
+        db::key_value::remove(&mut conn, "password_blocklist_path")
+            .unwrap_pretty("Error removing the key value pair");
+        Event::cli(Action::SettingChanged).target("password_blocklist_path").record(&mut conn);
+        println!("Successfuly turned off the password blocklist");
+        return;
+    }
+
+    let mut policy = validating::PasswordPolicy::default();
+    if let Err(err) = policy.load_blocklist(path) {
+        exit_with_error!("{}", err);
+    }
+    db::key_value::set(&mut conn, "password_blocklist_path", path)
+        .unwrap_pretty("Error setting the key value pair");
+    Event::cli(Action::SettingChanged).target("password_blocklist_path").record(&mut conn);
+
+    println!("Successfuly set the password blocklist with {} passwords, restart the server to start using it", policy.blocklist.len());
+}
+
+pub fn set_registration(db_path: &str, policy: &str) {
+    let policy: auth::RegistrationPolicy = match policy.parse() {
+        Ok(val) => val,
+        Err(_) => exit_with_error!("Invalid registration policy, expected one of: open, invite_only, closed"),
+    };
+
+    let pool = validate_db(db_path);
+    let mut conn = pool.get().unwrap();
+    db::key_value::set(&mut conn, "registration", &policy.to_string()).unwrap_pretty(
+        "Error setting the key value pair");
+    Event::cli(Action::SettingChanged).target("registration").record(&mut conn);
+
+    println!("Successfuly set the registration policy to \"{}\"", policy);
+}
+
+pub fn new_invitation(db_path: &str, uses: &str, days: &str) {
+    let uses: i32 = match uses.parse() {
+        Ok(val) if val > 0 => val,
+        _ => exit_with_error!("Invalid number of uses"),
+    };
+    // Same limit as the api
+    let days: i64 = match days.parse() {
+        Ok(val) if (1..=365).contains(&val) => val,
+        _ => exit_with_error!("Invalid number of days, expected 1 to 365"),
+    };
+
+    let pool = validate_db(db_path);
+    let mut conn = pool.get().unwrap();
+    let code = db::invitations::create(&mut conn, None, uses, chrono::Duration::days(days))
+        .unwrap_pretty("Error creating the invitation");
+    Event::cli(Action::InvitationCreated).record(&mut conn);
+
+    println!("Successfuly created a new invitation code: {}", code);
+}
+
+/// Writes the events as json lines, oldest first, so they can be appended to other logs
+pub fn export_audit_events(db_path: &str, path: &str, days: &str) {
+    let since = match days {
+        "all" => None,
+        _ => match days.parse::<i64>() {
+            Ok(val) if (1..=3650).contains(&val) => Some((chrono::Utc::now() - chrono::Duration::days(val)).timestamp()),
+            _ => exit_with_error!("Invalid number of days, expected 1 to 3650 or all"),
+        },
+    };
+
+    let pool = validate_db(db_path);
+    let mut conn = pool.get().unwrap();
+    let filter = db::audit_events::Filter {
+        since,
+        ..Default::default()
+    };
+    let events = db::audit_events::get_all(&mut conn, &filter).unwrap_pretty(
+        "Error loading the audit events");
+
+    let mut output: Box<dyn Write> = match path {
+        "-" => Box::new(io::stdout()),
+        _ => match std::fs::File::create(path) {
+            Ok(val) => Box::new(io::BufWriter::new(val)),
+            Err(err) => exit_with_error!("Couldn't create file at \"{}\": {}", path, err),
+        },
+    };
+    let count = events.len();
+    let result = events.into_iter()
+        .map(|event| serde_json::to_string(&audit::Entry::from(event)).unwrap())
+        .try_for_each(|line| writeln!(output, "{}", line))
+        .and_then(|_| output.flush());
+    if let Err(err) = result {
+        exit_with_error!("Couldn't write the audit events: {}", err);
+    }
+
+    // Keep stdout clean for piping
+    if path != "-" {
+        println!("Successfuly exported {} audit events to \"{}\"", count, path);
+    }
+}
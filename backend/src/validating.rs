@@ -1,38 +1,314 @@
-/// - 7 - 50 characters
-/// - 1 upper case letter
-/// - 1 lower case letter
-/// - 1 special from this list: !@#$%^&*()-_=+[]{}\|<>,./?
-pub fn is_valid_password(password: &str) -> bool {
-    if password.len() < 7 || 50 < password.len() {
-        return false;
+use crate::db;
+use serde::Serialize;
+use std::collections::HashSet;
+
+
+/// ## A rule broken by a new username, password or email
+/// Sent to the client, so it can tell the user what to fix
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Violation {
+    UsernameTooShort,
+    UsernameTooLong,
+    UsernameNoLetter,
+    UsernameWhitespace,
+    UsernameNotAscii,
+    PasswordTooShort,
+    PasswordTooLong,
+    PasswordNoLowercase,
+    PasswordNoUppercase,
+    PasswordNoDigit,
+    PasswordNoSpecial,
+    PasswordWhitespace,
+    /// Found in the list of breached or common passwords
+    PasswordBreached,
+    /// Same as the current one
+    PasswordReused,
+    InvalidEmail,
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Violation::UsernameTooShort => write!(f, "username_too_short"),
+            Violation::UsernameTooLong => write!(f, "username_too_long"),
+            Violation::UsernameNoLetter => write!(f, "username_no_letter"),
+            Violation::UsernameWhitespace => write!(f, "username_whitespace"),
+            Violation::UsernameNotAscii => write!(f, "username_not_ascii"),
+            Violation::PasswordTooShort => write!(f, "password_too_short"),
+            Violation::PasswordTooLong => write!(f, "password_too_long"),
+            Violation::PasswordNoLowercase => write!(f, "password_no_lowercase"),
+            Violation::PasswordNoUppercase => write!(f, "password_no_uppercase"),
+            Violation::PasswordNoDigit => write!(f, "password_no_digit"),
+            Violation::PasswordNoSpecial => write!(f, "password_no_special"),
+            Violation::PasswordWhitespace => write!(f, "password_whitespace"),
+            Violation::PasswordBreached => write!(f, "password_breached"),
+            Violation::PasswordReused => write!(f, "password_reused"),
+            Violation::InvalidEmail => write!(f, "invalid_email"),
+        }
+    }
+}
+
+
+/// Parses the length limits of a policy
+fn parse_lengths(min_length: &str, max_length: &str) -> Result<(usize, usize), String> {
+    let min_length: usize = min_length.parse().map_err(|_| "Invalid minimal length".to_owned())?;
+    let max_length: usize = max_length.parse().map_err(|_| "Invalid maximal length".to_owned())?;
+    if min_length == 0 || max_length < min_length {
+        return Err("The minimal length has to be positive and at most the maximal length".to_owned());
+    }
+    Ok((min_length, max_length))
+}
+
+
+/// ## Rules for new usernames
+/// Stored as "{min length} {max length} {any|ascii}" under the "username_policy" key in the key_value table
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UsernamePolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    /// Only allows printable ascii characters, so the usernames can't imitate each other with lookalike letters
+    pub ascii_only: bool,
+}
+
+impl Default for UsernamePolicy {
+    fn default() -> Self {
+        UsernamePolicy {
+            min_length: 3,
+            max_length: 25,
+            ascii_only: true,
+        }
+    }
+}
+
+impl UsernamePolicy {
+    pub fn new(min_length: &str, max_length: &str, charset: &str) -> Result<Self, String> {
+        let (min_length, max_length) = parse_lengths(min_length, max_length)?;
+        let ascii_only = match charset {
+            "any" => false,
+            "ascii" => true,
+            _ => return Err(format!("Unknown username charset \"{}\", expected one of: any, ascii", charset)),
+        };
+        Ok(UsernamePolicy { min_length, max_length, ascii_only })
+    }
+
+    /// ### Returns
+    /// The broken rules, empty if the username is valid
+    pub fn check(&self, username: &str) -> Vec<Violation> {
+        let mut violations = Vec::new();
+
+        // Counted in characters, so every script gets the same limits
+        let len = username.chars().count();
+        if len < self.min_length {
+            violations.push(Violation::UsernameTooShort);
+        }
+        if self.max_length < len {
+            violations.push(Violation::UsernameTooLong);
+        }
+        if ! username.chars().any(char::is_alphabetic) {
+            violations.push(Violation::UsernameNoLetter);
+        }
+        if username.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            violations.push(Violation::UsernameWhitespace);
+        }
+        // Whitespace and control characters already have their own violation
+        if self.ascii_only && ! username.is_ascii() {
+            violations.push(Violation::UsernameNotAscii);
+        }
+
+        violations
+    }
+}
+
+
+/// ## Rules for new passwords
+/// Stored as "{min length} {max length} {classes}" under the "password_policy" key in the key_value table,
+/// the classes are a comma separated list of: lower, upper, digit, special, or none
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    pub lowercase: bool,
+    pub uppercase: bool,
+    pub digit: bool,
+    /// Anything that isn't a letter, digit or whitespace
+    pub special: bool,
+    /// Lowercased breached or common passwords, loaded from the file under the "password_blocklist_path" key
+    #[serde(skip)]
+    pub blocklist: HashSet<String>,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length: 7,
+            max_length: 50,
+            lowercase: true,
+            uppercase: true,
+            digit: false,
+            special: true,
+            blocklist: HashSet::new(),
+        }
+    }
+}
+
+impl PasswordPolicy {
+    pub fn new(min_length: &str, max_length: &str, classes: &str) -> Result<Self, String> {
+        let (min_length, max_length) = parse_lengths(min_length, max_length)?;
+        let mut policy = PasswordPolicy {
+            min_length,
+            max_length,
+            lowercase: false,
+            uppercase: false,
+            digit: false,
+            special: false,
+            blocklist: HashSet::new(),
+        };
+
+        for class in classes.split(',').filter(|val| *val != "none") {
+            match class {
+                "lower" => policy.lowercase = true,
+                "upper" => policy.uppercase = true,
+                "digit" => policy.digit = true,
+                "special" => policy.special = true,
+                _ => return Err(format!("Unknown character class \"{}\", expected a list of: lower, upper, digit, special, or none", class)),
+            }
+        }
+        Ok(policy)
+    }
+
+    /// ## Reads the breached or common passwords from a file, one per line
+    pub fn load_blocklist(&mut self, path: &str) -> Result<(), String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| format!("Couldn't read the password blocklist at \"{}\": {}", path, err))?;
+        self.blocklist = content.lines()
+            .map(|val| val.trim().to_lowercase())
+            .filter(|val| ! val.is_empty())
+            .collect();
+        Ok(())
+    }
+
+    /// ### Returns
+    /// The broken rules, empty if the password is valid
+    pub fn check(&self, password: &str) -> Vec<Violation> {
+        let mut violations = Vec::new();
+
+        let len = password.chars().count();
+        if len < self.min_length {
+            violations.push(Violation::PasswordTooShort);
+        }
+        if self.max_length < len {
+            violations.push(Violation::PasswordTooLong);
+        }
+
+        // Letters of scripts without case count as both, otherwise such passwords could never be valid
+        let caseless = |c: char| c.is_alphabetic() && ! c.is_lowercase() && ! c.is_uppercase();
+        if self.lowercase && ! password.chars().any(|c| c.is_lowercase() || caseless(c)) {
+            violations.push(Violation::PasswordNoLowercase);
+        }
+        if self.uppercase && ! password.chars().any(|c| c.is_uppercase() || caseless(c)) {
+            violations.push(Violation::PasswordNoUppercase);
+        }
+        if self.digit && ! password.chars().any(char::is_numeric) {
+            violations.push(Violation::PasswordNoDigit);
+        }
+        if self.special && ! password.chars().any(|c| ! c.is_alphanumeric() && ! c.is_whitespace() && ! c.is_control()) {
+            violations.push(Violation::PasswordNoSpecial);
+        }
+        if password.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            violations.push(Violation::PasswordWhitespace);
+        }
+        if self.blocklist.contains(&password.to_lowercase()) {
+            violations.push(Violation::PasswordBreached);
+        }
+
+        violations
     }
+}
+
+
+/// ## Rules for new usernames and passwords
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct CredentialPolicy {
+    pub username: UsernamePolicy,
+    pub password: PasswordPolicy,
+}
+
+impl CredentialPolicy {
+    /// ## Loads the policies set with the -s:un, -s:pw and -s:pw:bl flags
+    /// The missing ones fall back to the defaults
+    pub fn load(conn: &mut db::Conn) -> Result<Self, String> {
+        let mut get = |key: &str| match db::key_value::get(conn, key) {
+            Ok(val) => Ok(Some(val)),
+            Err(diesel::result::Error::NotFound) => Ok(None),
+            Err(err) => Err(format!("Couldn't load \"{}\": {}", key, err)),
+        };
+
+        let username = match get("username_policy")? {
+            Some(val) => match val.split(' ').collect::<Vec<&str>>()[..] {
+                [min_length, max_length, charset] => UsernamePolicy::new(min_length, max_length, charset)?,
+                _ => return Err("Invalid username policy".to_owned()),
+            },
+            None => UsernamePolicy::default(),
+        };
+
+        let mut password = match get("password_policy")? {
+            Some(val) => match val.split(' ').collect::<Vec<&str>>()[..] {
+                [min_length, max_length, classes] => PasswordPolicy::new(min_length, max_length, classes)?,
+                _ => return Err("Invalid password policy".to_owned()),
+            },
+            None => PasswordPolicy::default(),
+        };
+        if let Some(path) = get("password_blocklist_path")? {
+            password.load_blocklist(&path)?;
+        }
+
+        Ok(CredentialPolicy { username, password })
+    }
+
+    /// ### Returns
+    /// The rules broken by the username and the password
+    pub fn check(&self, username: &str, password: &str) -> Vec<Violation> {
+        let mut violations = self.username.check(username);
+        violations.extend(self.password.check(password));
+        violations
+    }
+}
 
-    let has_lowercase = password.chars().any(|c| c.is_ascii_lowercase());
-    let has_uppercase = password.chars().any(|c| c.is_ascii_uppercase());
-    let has_no_space = ! password.contains(char::is_whitespace);
-    let has_special = password.chars().any(|c| "!@#$%^&*()-_=+[]{}\\|<>,./?".contains(c));
 
-    has_lowercase && has_uppercase && has_special && has_no_space
+/// - at most 254 characters
+/// - a local part and a domain separated by a single @
+/// - no whitespace or control characters, so it can't break out of a mail header
+pub fn is_valid_email(email: &str) -> bool {
+    if 254 < email.len() || email.chars().any(|c| c.is_whitespace() || c.is_control() || "<>,;\"".contains(c)) {
+        return false;
+    }
+
+    match email.split_once('@') {
+        Some((local, domain)) => ! local.is_empty() && ! domain.is_empty()
+            && ! domain.contains('@') && ! domain.starts_with('.') && ! domain.ends_with('.'),
+        None => false,
+    }
 }
 
-/// - 3 - 25 characters
+/// - 3 - 255 characters
 /// - at least one letter
-pub fn is_valid_username(username: &str) -> bool {
-    let len = username.len();
-    if ! (3..=25).contains(&len) {
+pub fn is_valid_ingredient_name(name: &str) -> bool {
+    let len = name.len();
+    if ! (3..=255).contains(&len) {
         return false;
     };
 
-    let has_letter = username.chars().any(|c| c.is_ascii_lowercase() || c.is_ascii_uppercase());
+    let has_letter = name.chars().any(|c| c.is_ascii_lowercase() || c.is_ascii_uppercase());
 
     has_letter
 }
 
-/// - 3 - 255 characters
+/// - 5 - 32 characters
 /// - at least one letter
-pub fn is_valid_ingredient_name(name: &str) -> bool {
+pub fn is_valid_recipe_name(name: &str) -> bool {
     let len = name.len();
-    if ! (3..=255).contains(&len) {
+    if ! (5..=32).contains(&len) {
         return false;
     };
 
@@ -50,4 +326,47 @@ pub fn is_valid_socket(socket: &str) -> bool {
         }
         Err(_) => false,
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_policy() {
+        let policy = CredentialPolicy::default();
+        assert!(policy.check("BestChef", "Password1!").is_empty());
+        assert_eq!(policy.username.check("a b"), vec![Violation::UsernameWhitespace]);
+        assert_eq!(policy.password.check("password"), vec![Violation::PasswordNoUppercase, Violation::PasswordNoSpecial]);
+        assert_eq!(policy.password.check("Pa1!"), vec![Violation::PasswordTooShort]);
+    }
+
+    #[test]
+    fn unicode() {
+        let policy = CredentialPolicy {
+            username: UsernamePolicy::new("3", "25", "any").unwrap(),
+            ..CredentialPolicy::default()
+        };
+        // Lengths are counted in characters, not bytes
+        assert!(policy.check("Kuchař", "Žluťoučký-kůň").is_empty());
+        assert!(policy.password.check("密码密码密码密码!").is_empty());
+        assert_eq!(policy.password.check("ŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽŽ!"),
+            vec![Violation::PasswordTooLong, Violation::PasswordNoLowercase]);
+
+        // Lookalike letters are only allowed once the charset is set to any
+        assert_eq!(UsernamePolicy::default().check("Kuchař"), vec![Violation::UsernameNotAscii]);
+    }
+
+    #[test]
+    fn configured_policy() {
+        let mut policy = PasswordPolicy::new("10", "64", "digit").unwrap();
+        policy.blocklist.insert("password123".to_owned());
+        assert!(policy.check("long passphrase 1").contains(&Violation::PasswordWhitespace));
+        assert!(policy.check("correcthorse1").is_empty());
+        assert_eq!(policy.check("Password123"), vec![Violation::PasswordBreached]);
+
+        assert!(PasswordPolicy::new("8", "64", "none").unwrap().check("abcdefgh").is_empty());
+        assert!(PasswordPolicy::new("8", "64", "lower,emoji").is_err());
+        assert!(PasswordPolicy::new("9", "8", "lower").is_err());
+    }
+}